@@ -0,0 +1,35 @@
+//! Exposes the resolved version of the serpent library as `SERPENT_VERSION`.
+use std::{env, fs, path::Path};
+
+fn main() {
+    let lock_path = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock_path.display());
+
+    let version = fs::read_to_string(&lock_path)
+        .ok()
+        .and_then(|lock| serpent_version(&lock))
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=SERPENT_VERSION={}", version);
+}
+
+/// Extracts the version and source of the serpent package from the contents of
+/// a Cargo.lock, eg. "0.1.0 git+https://github.com/hegza/serpent-rs#be8fad28".
+fn serpent_version(lock: &str) -> Option<String> {
+    let package = lock
+        .split("[[package]]")
+        .find(|package| package.lines().any(|line| line == "name = \"serpent\""))?;
+
+    let field = |name: &str| {
+        package.lines().find_map(|line| {
+            line.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix(" = "))
+                .map(|value| value.trim_matches('"').to_owned())
+        })
+    };
+
+    let version = field("version")?;
+    Some(match field("source") {
+        Some(source) => format!("{} {}", version, source),
+        None => version,
+    })
+}
@@ -0,0 +1,316 @@
+//! Project configuration with defaults for command-line options.
+//!
+//! The configuration is read from a `serpent.toml` or from the `[tool.serpent]`
+//! table of a `pyproject.toml`, whichever is found first when walking upward
+//! from INPUT. Keys are named after the long command-line flags, eg.
+//! `emit-manifest = true`. Top-level keys apply to every subcommand and a table
+//! named after a subcommand, eg. `[transpile]` or `[tp]`, overrides them for
+//! that subcommand.
+use crate::error::CliError;
+use fs_err as fs;
+use log::LevelFilter;
+use toml::{map::Map as TomlMap, Value as TomlValue};
+
+use std::{env, fmt, iter, path};
+
+/// A type alias for `Result<T, crate::error::CliError>`.
+pub type Result<T> = std::result::Result<T, CliError>;
+
+const CONFIG_FILE: &str = "serpent.toml";
+const PYPROJECT_FILE: &str = "pyproject.toml";
+
+/// Keys whose values are paths, resolved relative to the configuration file.
+const PATH_KEYS: &[&str] = &[
+    "output",
+    "remap-file",
+    "python-deps",
+    "cache-dir",
+    "file",
+    "sarif",
+];
+
+/// Pairs of flags that override each other, so that eg. `--omit-manifest`
+/// overrides `emit-manifest = true` in the configuration file.
+const OVERRIDES: &[(&str, &str)] = &[
+    ("emit-manifest", "omit-manifest"),
+    ("remap-file", "no-remap"),
+    ("merge", "no-merge"),
+];
+
+/// Other names of sections, so that the table of `transpile` may also be named
+/// `[tp]` after its alias.
+const SECTION_ALIASES: &[(&str, &str)] = &[("transpile", "tp")];
+
+/// A project configuration file, or an empty configuration if none was found.
+#[derive(Debug, Default)]
+pub struct ProjectConfig {
+    path: Option<path::PathBuf>,
+    table: TomlMap<String, TomlValue>,
+}
+
+impl ProjectConfig {
+    /// Finds the configuration of the project containing `input`, starting
+    /// from the current directory for stdin or no input.
+    pub fn discover(input: Option<&str>) -> Result<ProjectConfig> {
+        let start = match input {
+            Some(input) if input != "-" => fs::canonicalize(crate::to_path(input)?)?,
+            _ => env::current_dir()?,
+        };
+
+        for dir in start.ancestors().filter(|dir| dir.is_dir()) {
+            let config_path = dir.join(CONFIG_FILE);
+            if config_path.is_file() {
+                return ProjectConfig::load(config_path);
+            }
+            let pyproject_path = dir.join(PYPROJECT_FILE);
+            if pyproject_path.is_file() {
+                let config = ProjectConfig::load(pyproject_path)?;
+                if config.path.is_some() {
+                    return Ok(config);
+                }
+            }
+        }
+
+        Ok(ProjectConfig::default())
+    }
+
+    /// Reads a `serpent.toml`, or the `[tool.serpent]` table of a
+    /// `pyproject.toml`.
+    fn load(path: path::PathBuf) -> Result<ProjectConfig> {
+        let table = match fs::read_to_string(&path)?.parse::<TomlValue>()? {
+            TomlValue::Table(table) => table,
+            value => return Err(CliError::TomlContentError(value, "table")),
+        };
+
+        if path
+            .file_name()
+            .map_or(false, |name| name == PYPROJECT_FILE)
+        {
+            let serpent = table
+                .get("tool")
+                .and_then(|tool| tool.get("serpent"))
+                .cloned();
+            return match serpent {
+                Some(TomlValue::Table(table)) => Ok(ProjectConfig {
+                    path: Some(path),
+                    table,
+                }),
+                Some(value) => Err(CliError::TomlContentError(value, "table")),
+                // A pyproject.toml without serpent configuration is skipped
+                None => Ok(ProjectConfig::default()),
+            };
+        }
+
+        Ok(ProjectConfig {
+            path: Some(path),
+            table,
+        })
+    }
+
+    /// Returns the path of the configuration file, if one was found.
+    pub fn path(&self) -> Option<&path::Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the value of `key` in the table of `section` or of one of its
+    /// aliases, or the top-level value of `key`.
+    pub fn get(&self, section: &str, key: &str) -> Option<&TomlValue> {
+        let aliases = SECTION_ALIASES
+            .iter()
+            .filter(|(name, _)| *name == section)
+            .map(|(_, alias)| *alias);
+        let in_section = iter::once(section)
+            .chain(aliases)
+            .filter_map(|section| self.table.get(section))
+            .filter_map(|table| table.as_table())
+            .find_map(|table| table.get(key));
+        in_section.or_else(|| match self.table.get(key) {
+            Some(TomlValue::Table(_)) => None,
+            value => value,
+        })
+    }
+
+    /// Returns the module and global log levels set with `log-level`, if any.
+    pub fn log_level(&self) -> Result<Option<(LevelFilter, LevelFilter)>> {
+        let level = match self.table.get("log-level") {
+            Some(TomlValue::String(level)) => level,
+            Some(value) => return Err(CliError::TomlContentError(value.clone(), "String")),
+            None => return Ok(None),
+        };
+
+        // The same levels as with the equivalent number of -v flags
+        Ok(Some(match level.as_str() {
+            "off" => (LevelFilter::Off, LevelFilter::Off),
+            "error" => (LevelFilter::Error, LevelFilter::Error),
+            "warn" => (LevelFilter::Warn, LevelFilter::Error),
+            "info" => (LevelFilter::Info, LevelFilter::Error),
+            "debug" => (LevelFilter::Debug, LevelFilter::Warn),
+            "trace" => (LevelFilter::Trace, LevelFilter::Info),
+            _ => return Err(CliError::InvalidArgument("log-level", level.clone())),
+        }))
+    }
+
+    /// Resolves a path in the configuration relative to the configuration
+    /// file.
+    fn resolve_path(&self, value: &str) -> String {
+        match self.path.as_ref().and_then(|path| path.parent()) {
+            Some(dir) => dir.join(value).to_string_lossy().into_owned(),
+            None => value.to_owned(),
+        }
+    }
+}
+
+/// Where the value of an option came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    CommandLine,
+    File(path::PathBuf),
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::CommandLine => write!(f, "command line"),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// Command-line options of a subcommand with defaults from the project
+/// configuration. Mirrors the `clap::ArgMatches` accessors.
+pub struct Options<'a> {
+    matches: &'a clap::ArgMatches<'a>,
+    project: &'a ProjectConfig,
+    /// The section of the configuration for the subcommand
+    section: &'static str,
+}
+
+impl<'a> Options<'a> {
+    pub fn new(
+        matches: &'a clap::ArgMatches<'a>,
+        project: &'a ProjectConfig,
+        section: &'static str,
+    ) -> Options<'a> {
+        Options {
+            matches,
+            project,
+            section,
+        }
+    }
+
+    /// Returns true if the flag `name` is set on the command line or in the
+    /// configuration.
+    pub fn is_present(&self, name: &str) -> Result<bool> {
+        if self.matches.is_present(name) {
+            return Ok(true);
+        }
+        match self.from_file(name) {
+            Some(TomlValue::Boolean(value)) => Ok(*value),
+            Some(value) => Err(CliError::TomlContentError(value.clone(), "Boolean")),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the value of `name` from the command line, the configuration or
+    /// the default value of the argument, in this order.
+    pub fn value_of(&self, name: &str) -> Result<Option<String>> {
+        if self.matches.occurrences_of(name) > 0 {
+            return Ok(self.matches.value_of(name).map(str::to_owned));
+        }
+        match self.from_file(name) {
+            Some(value) => Ok(Some(self.to_string(name, value)?)),
+            None => Ok(self.matches.value_of(name).map(str::to_owned)),
+        }
+    }
+
+    /// Returns all values of `name` from the command line or the
+    /// configuration, where the values may be given as an array.
+    pub fn values_of(&self, name: &str) -> Result<Vec<String>> {
+        if self.matches.occurrences_of(name) > 0 {
+            return Ok(self
+                .matches
+                .values_of(name)
+                .map(|values| values.map(str::to_owned).collect())
+                .unwrap_or_default());
+        }
+        match self.from_file(name) {
+            Some(TomlValue::Array(values)) => values
+                .iter()
+                .map(|value| self.to_string(name, value))
+                .collect(),
+            Some(value) => Ok(vec![self.to_string(name, value)?]),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Converts a value in the configuration into a command-line value.
+    fn to_string(&self, name: &str, value: &TomlValue) -> Result<String> {
+        match value {
+            TomlValue::String(value) if PATH_KEYS.contains(&name) => {
+                Ok(self.project.resolve_path(value))
+            }
+            TomlValue::String(value) => Ok(value.clone()),
+            TomlValue::Integer(value) => Ok(value.to_string()),
+            value => Err(CliError::TomlContentError(value.clone(), "String")),
+        }
+    }
+
+    /// Returns where the value of `name` comes from.
+    pub fn source_of(&self, name: &str) -> Source {
+        if self.matches.occurrences_of(name) > 0 {
+            Source::CommandLine
+        } else if self.from_file(name).is_some() {
+            // Unwrap is safe because values only come from a file that exists
+            Source::File(self.project.path().unwrap().to_path_buf())
+        } else {
+            Source::Default
+        }
+    }
+
+    /// Returns the value of `name` in the configuration, unless it's
+    /// overridden by its counterpart on the command line.
+    fn from_file(&self, name: &str) -> Option<&TomlValue> {
+        let overridden = OVERRIDES.iter().any(|&(a, b)| {
+            (a == name && self.matches.is_present(b)) || (b == name && self.matches.is_present(a))
+        });
+        if overridden {
+            None
+        } else {
+            self.project.get(self.section, name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(contents: &str) -> ProjectConfig {
+        ProjectConfig {
+            path: None,
+            table: contents.parse::<TomlValue>().unwrap().try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn get_reads_the_section_under_its_alias() {
+        let project =
+            project("fmt = false\n[tp]\nfmt = true\nlines = true\n[transpile]\nlines = false\n");
+
+        assert_eq!(
+            project.get("transpile", "fmt"),
+            Some(&TomlValue::Boolean(true))
+        );
+        // The section under its own name comes first
+        assert_eq!(
+            project.get("transpile", "lines"),
+            Some(&TomlValue::Boolean(false))
+        );
+        assert_eq!(
+            project.get("steps", "fmt"),
+            Some(&TomlValue::Boolean(false))
+        );
+    }
+}
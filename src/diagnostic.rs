@@ -0,0 +1,199 @@
+//! Compiler-style diagnostics for errors reported by the transpiler, eg.:
+//!
+//! ```text
+//! error[SP0001]: parse error at line 3 column 5
+//!   --> examples/black_scholes/__init__.py:3:5
+//!    |
+//!  3 | def f(x)
+//!    |     ^
+//! ```
+//!
+//! The error codes are stable, so that documentation can refer to them.
+use crate::error::CliError;
+use fs_err as fs;
+use serpent::ApiError;
+
+use std::error::Error;
+use std::{fmt, path};
+
+const SYNTAX_CODE: &str = "SP0001";
+const UNSUPPORTED_CODE: &str = "SP0002";
+const REMAP_CODE: &str = "SP0003";
+const IO_CODE: &str = "SP0004";
+/// The code of errors that match no other code.
+const OTHER_CODE: &str = "SP0000";
+
+/// Error codes and their descriptions.
+const CODES: &[(&str, &str)] = &[
+    (SYNTAX_CODE, "Syntax error in the Python source"),
+    (
+        UNSUPPORTED_CODE,
+        "Python construct that cannot be transpiled",
+    ),
+    (REMAP_CODE, "Invalid remap"),
+    (IO_CODE, "Python source cannot be read"),
+];
+
+/// A transpiler error with its location in the Python source, if known.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub message: String,
+    pub path: Option<path::PathBuf>,
+    /// 1-based line and column of the error
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// The Python source line at `line`
+    source_line: Option<String>,
+    /// The messages of the underlying errors, outermost first
+    causes: Vec<String>,
+    notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// Creates a diagnostic for an error of transpiling `path`.
+    pub fn new(error: &ApiError, path: Option<&path::Path>) -> Diagnostic {
+        let (code, line, column) = classify(error);
+        let source_line = match (path, line) {
+            (Some(path), Some(line)) => fs::read_to_string(path).ok().and_then(|source| {
+                source
+                    .lines()
+                    .nth(line.saturating_sub(1))
+                    .map(str::to_owned)
+            }),
+            _ => None,
+        };
+
+        let mut causes = vec![];
+        let mut source = error.source();
+        while let Some(cause) = source {
+            causes.push(cause.to_string());
+            source = cause.source();
+        }
+
+        Diagnostic {
+            code,
+            message: error.to_string(),
+            path: path.map(path::Path::to_path_buf),
+            line,
+            column,
+            source_line,
+            causes,
+            notes: vec![],
+        }
+    }
+
+    /// Adds a note rendered below the error, eg. on how the file at fault was
+    /// found.
+    pub fn note(mut self, note: &str) -> Diagnostic {
+        self.notes.push(note.to_owned());
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "error[{}]: {}", self.code, self.message)?;
+
+        let gutter = self.line.map_or(0, |line| line.to_string().len());
+        let pad = " ".repeat(gutter);
+        if let Some(path) = &self.path {
+            write!(f, "{}--> {}", pad, path.display())?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+                if let Some(column) = self.column {
+                    write!(f, ":{}", column)?;
+                }
+            }
+            writeln!(f)?;
+        }
+
+        if let (Some(line), Some(source_line)) = (self.line, &self.source_line) {
+            writeln!(f, "{} |", pad)?;
+            writeln!(f, "{} | {}", line, source_line)?;
+            if let Some(column) = self.column {
+                let (offset, width) = underline(source_line, column);
+                writeln!(f, "{} | {}{}", pad, " ".repeat(offset), "^".repeat(width))?;
+            }
+        }
+
+        for cause in &self.causes {
+            writeln!(f, "{} = caused by: {}", pad, cause)?;
+        }
+        for note in &self.notes {
+            writeln!(f, "{} = note: {}", pad, note)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the code of a transpiler error and its 1-based line and column, if
+/// known.
+fn classify(error: &ApiError) -> (&'static str, Option<usize>, Option<usize>) {
+    match error {
+        ApiError::Parse(e) => (
+            SYNTAX_CODE,
+            Some(e.location.row()),
+            Some(e.location.column()),
+        ),
+        ApiError::Unsupported(_) => (UNSUPPORTED_CODE, None, None),
+        ApiError::Remap(_) => (REMAP_CODE, None, None),
+        ApiError::Io(_) => (IO_CODE, None, None),
+        _ => (OTHER_CODE, None, None),
+    }
+}
+
+/// Returns the description of an error code.
+pub fn description(code: &str) -> &'static str {
+    CODES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or("Other transpiler error", |(_, description)| description)
+}
+
+/// Returns the offset and width of the underline at the 1-based `column` of a
+/// source line, covering the word that starts there.
+fn underline(source_line: &str, column: usize) -> (usize, usize) {
+    let chars = source_line.chars().collect::<Vec<_>>();
+    let offset = column.saturating_sub(1).min(chars.len());
+    let width = chars[offset..]
+        .iter()
+        .take_while(|c| c.is_alphanumeric() || **c == '_')
+        .count();
+    (offset, width.max(1))
+}
+
+/// Converts an error of transpiling a file into a diagnostic. `display_path`
+/// replaces the path in the diagnostic, eg. for source from stdin.
+pub fn file_error(
+    path: &path::Path,
+    display_path: Option<&path::Path>,
+    error: ApiError,
+) -> CliError {
+    let mut diagnostic = Diagnostic::new(&error, Some(path));
+    if let Some(display_path) = display_path {
+        diagnostic.path = Some(display_path.to_path_buf());
+    }
+    CliError::Diagnostics(vec![diagnostic])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn underline_covers_the_word_at_the_column() {
+        assert_eq!(underline("def f(x)", 5), (4, 1));
+        assert_eq!(underline("x = value + 1", 5), (4, 5));
+        // Punctuation is underlined by itself
+        assert_eq!(underline("def f(x)", 6), (5, 1));
+        assert_eq!(underline("def f(x)", 0), (0, 3));
+    }
+
+    #[test]
+    fn underline_past_the_end_of_the_line_points_after_it() {
+        assert_eq!(underline("abc", 4), (3, 1));
+        assert_eq!(underline("abc", 10), (3, 1));
+        assert_eq!(underline("", 1), (0, 1));
+    }
+}
@@ -1,7 +1,29 @@
+use crate::diagnostic::Diagnostic;
 use flexi_logger::FlexiLoggerError;
+use itertools::Itertools;
 use thiserror::Error as ThisError;
 
-use std::io;
+use std::error::Error;
+use std::{fmt, io, path::PathBuf};
+
+/// Exit codes of the CLI. Wrapper scripts branch on these, so the values must
+/// not change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitCode {
+    /// Any other failure, eg. `cargo check` failed or the output differs
+    Failure = 1,
+    /// Invalid arguments or paths
+    Usage = 2,
+    /// Reading or writing a file failed
+    Io = 3,
+    /// The remap file or another TOML file is invalid
+    Config = 4,
+    /// The input could not be transpiled
+    Transpile = 5,
+    /// Some files failed to transpile with `--keep-going`, the rest were
+    /// written
+    Partial = 6,
+}
 
 /// An error that occurred while running the CLI.
 #[derive(ThisError, Debug)]
@@ -14,18 +36,175 @@ pub enum CliError {
     PathIsDirectory(String),
     #[error("{0:?} is not a directory")]
     PathIsFile(String),
-    #[error("Redundant parameter")]
+    #[error("{0:?} already exists, use --force to overwrite")]
+    FileExists(PathBuf),
+    #[error("Reading INPUT from stdin is not supported by `{0}`")]
+    StdinNotSupported(&'static str),
+    #[error("Invalid value for `{0}`: {1:?}")]
+    InvalidArgument(&'static str, String),
+    #[error("{0:?} is not a file of the transpiled module")]
+    NotInModule(PathBuf),
+    #[error("No `{0}` named {1:?} found")]
+    SymbolNotFound(&'static str, String),
+    #[error("No Python line produced line {1} of {0:?}")]
+    NoPythonLine(PathBuf, usize),
+    #[error("Missing argument: {0}")]
+    MissingArgument(String),
+    #[error("Redundant parameter: {0}")]
     RedundantParameter(String),
+    /// An argument that needs a module as INPUT, eg. "keep-going"
+    #[error("`{0}` only makes sense when transpiling an input module")]
+    RequiresModule(&'static str),
+    /// An argument that needs a module as INPUT and an output directory, eg.
+    /// "check"
+    #[error("`{0}` only makes sense when transpiling an input module into an output directory")]
+    RequiresOutputDir(&'static str),
+    /// Invalid command line, with the message and usage rendered by clap
+    #[error("{}", .0.message)]
+    Usage(clap::Error),
     #[error("Transpiler error")]
     SerpentError(#[from] serpent::ApiError),
-    #[error("cargo-toml-builder error")]
-    CargoBuilderError(#[from] cargo_toml_builder::Error),
+    /// Transpiler errors located in the Python source
+    #[error("{}", .0.iter().join("\n"))]
+    Diagnostics(Vec<Diagnostic>),
     #[error("TOML deserialization error")]
     TomlError(#[from] toml::de::Error),
+    #[error("TOML serialization error")]
+    TomlSerError(#[from] toml::ser::Error),
     /// First is input, second is expected, eg. "table"
-    #[error("TOML contents are not of expected format {0:?} should be '{}'")]
+    #[error("TOML contents are not of expected format {0:?} should be '{1}'")]
     TomlContentError(toml::Value, &'static str),
+    #[error("JSON error")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Manifest not found at {0:?}")]
+    ManifestNotFound(PathBuf),
+    #[error("{0:?} declares modules, but the module is not a library")]
+    ModulesWithoutLib(PathBuf),
+    #[error("`cargo check` failed with {0} error(s)")]
+    CargoCheckFailed(usize),
+    #[error("{0} file(s) differ from the transpiled output")]
+    OutputDiffers(usize),
+    #[error("Merging hand edits left conflicts in {0} file(s), resolve the conflict markers")]
+    MergeConflicts(usize),
+    #[error("{0} file(s) failed to transpile")]
+    FilesFailed(usize),
+    #[error("rustfmt failed: {0}")]
+    RustfmtFailed(String),
+    #[error("File watcher error")]
+    WatchError(#[from] notify::Error),
+    #[error("File watcher disconnected")]
+    WatchDisconnected,
+    #[error("Cannot create thread pool")]
+    ThreadPoolError(#[from] rayon::ThreadPoolBuildError),
     /// An I/O error that occurred while reading or writing a file.
     #[error("IO error")]
     Io(#[from] io::Error),
 }
+
+impl CliError {
+    /// Returns the exit code for the error.
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            CliError::FileOrDirectoryNotFound(_)
+            | CliError::PathIsDirectory(_)
+            | CliError::PathIsFile(_)
+            | CliError::FileExists(_)
+            | CliError::StdinNotSupported(_)
+            | CliError::InvalidArgument(..)
+            | CliError::NotInModule(_)
+            | CliError::SymbolNotFound(..)
+            | CliError::NoPythonLine(..)
+            | CliError::MissingArgument(_)
+            | CliError::RedundantParameter(_)
+            | CliError::RequiresModule(_)
+            | CliError::RequiresOutputDir(_)
+            | CliError::Usage(_)
+            | CliError::ManifestNotFound(_)
+            | CliError::ModulesWithoutLib(_) => ExitCode::Usage,
+            CliError::Io(_) | CliError::WatchError(_) => ExitCode::Io,
+            CliError::TomlError(_) | CliError::TomlSerError(_) | CliError::TomlContentError(..) => {
+                ExitCode::Config
+            }
+            CliError::SerpentError(_) | CliError::Diagnostics(_) => ExitCode::Transpile,
+            CliError::FilesFailed(_) => ExitCode::Partial,
+            CliError::LogError(_)
+            | CliError::JsonError(_)
+            | CliError::CargoCheckFailed(_)
+            | CliError::OutputDiffers(_)
+            | CliError::MergeConflicts(_)
+            | CliError::RustfmtFailed(_)
+            | CliError::WatchDisconnected
+            | CliError::ThreadPoolError(_) => ExitCode::Failure,
+        }
+    }
+
+    /// Returns a hint on how to fix the error, if there is one.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            CliError::RequiresOutputDir("emit-manifest") => {
+                Some("did you mean --emit-manifest with -o DIR, where INPUT is a module directory?")
+            }
+            CliError::RequiresOutputDir(_) => {
+                Some("pass a module directory as INPUT and an output directory with -o DIR")
+            }
+            CliError::RequiresModule(_) => Some("pass a module directory as INPUT"),
+            CliError::FileOrDirectoryNotFound(_) => {
+                Some("relative paths start from the current directory")
+            }
+            CliError::StdinNotSupported(_) => {
+                Some("save the source into a file and pass its path as INPUT")
+            }
+            CliError::ManifestNotFound(_) => {
+                Some("add --emit-manifest to write a Cargo.toml into the output directory")
+            }
+            CliError::ModulesWithoutLib(_) => Some(
+                "add an __init__.py to INPUT, so that the binaries use its modules as a library",
+            ),
+            CliError::TomlError(_) | CliError::TomlContentError(..) => {
+                Some("check the syntax of the remap file and of serpent.toml")
+            }
+            CliError::MergeConflicts(_) => Some("or pass --no-merge to overwrite the hand edits"),
+            CliError::OutputDiffers(_) => {
+                Some("run `serpent tp` with the same options to update the output")
+            }
+            CliError::FilesFailed(_) => {
+                Some("the failed files were written as stubs with a `compile_error!`")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Renders an error for the user, with its causes and a hint, eg.:
+///
+/// ```text
+/// error: IO error
+///  = caused by: failed to open file `Remap.toml`: No such file or directory
+///  = hint: relative paths start from the current directory
+/// ```
+///
+/// Transpiler errors are rendered as diagnostics and command line errors as
+/// clap renders them.
+pub struct Report<'a>(pub &'a CliError);
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let error = self.0;
+        match error {
+            CliError::Diagnostics(_) => return write!(f, "{}", error),
+            CliError::Usage(_) => return writeln!(f, "{}", error),
+            CliError::SerpentError(e) => return write!(f, "{}", Diagnostic::new(e, None)),
+            _ => writeln!(f, "error: {}", error)?,
+        }
+
+        let mut source = error.source();
+        while let Some(cause) = source {
+            writeln!(f, " = caused by: {}", cause)?;
+            source = cause.source();
+        }
+        if let Some(hint) = error.hint() {
+            writeln!(f, " = hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! Mapping between the lines of a Python source file and the lines of the Rust
+//! it was transpiled into.
+use std::ops::Range;
+
+/// Maps Python source lines into ranges of lines in the transpiled Rust
+/// output. All line numbers are 1-based and ranges are end-exclusive.
+#[derive(Debug, Clone, Default)]
+pub struct LineMap {
+    entries: Vec<LineMapEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LineMapEntry {
+    pub python_line: usize,
+    pub rust_lines: Range<usize>,
+}
+
+impl LineMap {
+    /// Builds a line map using the per-line transpilation traces.
+    ///
+    /// `trace` is called for each non-empty Python line and is expected to
+    /// return the four transpilation steps for that line, as returned by
+    /// `trace_steps_for_line`. The Rust source in the last step is then located
+    /// in `rust_target`. Lines that cannot be traced or located are skipped.
+    pub fn build<F>(python_source: &str, rust_target: &str, mut trace: F) -> LineMap
+    where
+        F: FnMut(usize) -> Result<Vec<String>, serpent::ApiError>,
+    {
+        let rust_lines = rust_target.lines().map(str::trim).collect::<Vec<_>>();
+
+        let mut entries = vec![];
+        // Python lines produce Rust in order, so the search for the next line can
+        // start where the previous one was found
+        let mut cursor = 0;
+        for (line_idx, line) in python_source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let python_line = line_idx + 1;
+            let steps = match trace(python_line) {
+                Ok(steps) => steps,
+                Err(_) => continue,
+            };
+            let snippet = match steps.get(3) {
+                Some(rust) => rust
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty())
+                    .collect::<Vec<_>>(),
+                None => continue,
+            };
+            let first = match snippet.first() {
+                Some(first) => first,
+                None => continue,
+            };
+
+            if let Some(offset) = rust_lines[cursor..].iter().position(|l| l == first) {
+                let start = cursor + offset;
+                entries.push(LineMapEntry {
+                    python_line,
+                    rust_lines: start + 1..start + 1 + snippet.len(),
+                });
+                cursor = start + 1;
+            }
+        }
+
+        LineMap { entries }
+    }
+
+    /// Carries the map over onto `modified`, a version of the `original` Rust
+    /// the map was built for with lines reflowed, eg. by rustfmt. The lines
+    /// are matched with a diff, and a changed line maps onto the place of the
+    /// lines that replaced it.
+    pub fn carry_over(&self, original: &str, modified: &str) -> LineMap {
+        let mapping = line_mapping(original, modified);
+        let map_line = |line: usize| mapping.get(line - 1).copied().unwrap_or(line);
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let start = map_line(entry.rust_lines.start);
+                let end = map_line(entry.rust_lines.end - 1) + 1;
+                LineMapEntry {
+                    python_line: entry.python_line,
+                    rust_lines: start..end.max(start + 1),
+                }
+            })
+            .collect();
+        LineMap { entries }
+    }
+
+    pub fn entries(&self) -> &[LineMapEntry] {
+        &self.entries
+    }
+
+    /// Returns the Python line that produced the given Rust line, if any.
+    ///
+    /// Rust lines that fall between mapped ranges are attributed to the closest
+    /// preceding Python line.
+    pub fn python_line_for(&self, rust_line: usize) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|e| e.rust_lines.contains(&rust_line))
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .filter(|e| e.rust_lines.start <= rust_line)
+                    .last()
+            })
+            .map(|e| e.python_line)
+    }
+}
+
+/// Returns the 1-based line in `modified` for each line of `original`.
+fn line_mapping(original: &str, modified: &str) -> Vec<usize> {
+    let line_count = original.lines().count();
+    let mut mapping = Vec::with_capacity(line_count);
+    let mut modified_line = 1;
+    for hunk in diffy::create_patch(original, modified).hunks() {
+        // The lines before the hunk are unchanged
+        while mapping.len() + 1 < hunk.old_range().start() {
+            mapping.push(modified_line);
+            modified_line += 1;
+        }
+        for line in hunk.lines() {
+            match line {
+                diffy::Line::Context(_) => {
+                    mapping.push(modified_line);
+                    modified_line += 1;
+                }
+                diffy::Line::Delete(_) => mapping.push(modified_line),
+                diffy::Line::Insert(_) => modified_line += 1,
+            }
+        }
+    }
+    while mapping.len() < line_count {
+        mapping.push(modified_line);
+        modified_line += 1;
+    }
+    mapping
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(python_line: usize, rust_lines: Range<usize>) -> LineMapEntry {
+        LineMapEntry {
+            python_line,
+            rust_lines,
+        }
+    }
+
+    #[test]
+    fn carry_over_follows_reflowed_lines() {
+        let line_map = LineMap {
+            entries: vec![
+                entry(1, 1..2),
+                entry(2, 2..3),
+                entry(3, 3..4),
+                entry(4, 4..5),
+            ],
+        };
+        let original = "a();\nb(x, y);\nc();\nd();\n";
+        let modified = "// header\na();\nb(\n    x, y);\nc();\nd();\n";
+
+        let carried = line_map.carry_over(original, modified);
+
+        let ranges = carried
+            .entries()
+            .iter()
+            .map(|e| (e.python_line, e.rust_lines.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(1, 2..3), (2, 3..4), (3, 5..6), (4, 6..7)]);
+        // The continuation of a reflowed line belongs to the line before it
+        assert_eq!(carried.python_line_for(4), Some(2));
+    }
+
+    #[test]
+    fn carry_over_keeps_unchanged_lines() {
+        let line_map = LineMap {
+            entries: vec![entry(1, 1..3), entry(3, 3..4)],
+        };
+        let rust = "fn f() {\n}\ng();\n";
+
+        let carried = line_map.carry_over(rust, rust);
+
+        assert_eq!(carried.entries()[0].rust_lines, 1..3);
+        assert_eq!(carried.entries()[1].rust_lines, 3..4);
+    }
+
+    /// Traces each Python line into the Rust in `rust_by_line`, by line.
+    fn trace<'a>(
+        rust_by_line: &'a [(usize, &'a str)],
+    ) -> impl FnMut(usize) -> Result<Vec<String>, serpent::ApiError> + 'a {
+        move |line| {
+            let rust = rust_by_line
+                .iter()
+                .find(|(l, _)| *l == line)
+                .map_or("", |(_, rust)| rust);
+            Ok(vec![
+                String::new(),
+                String::new(),
+                String::new(),
+                rust.to_owned(),
+            ])
+        }
+    }
+
+    #[test]
+    fn build_locates_the_rust_of_each_python_line() {
+        let python = "a = 1\n\n# comment\nif a:\n    b = 2\n";
+        let rust = "fn main() {\n    let a = 1;\n    if a {\n        let b = 2;\n    }\n}\n";
+        let rust_by_line = [
+            (1, "let a = 1;"),
+            (4, "if a {\n    let b = 2;\n}"),
+            (5, "let b = 2;"),
+        ];
+
+        let line_map = LineMap::build(python, rust, trace(&rust_by_line));
+
+        let ranges = line_map
+            .entries()
+            .iter()
+            .map(|e| (e.python_line, e.rust_lines.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(1, 2..3), (4, 3..6), (5, 4..5)]);
+        assert_eq!(line_map.python_line_for(1), None);
+        assert_eq!(line_map.python_line_for(5), Some(4));
+    }
+
+    #[test]
+    fn build_maps_repeated_lines_in_order_and_skips_missing_ones() {
+        let python = "f()\ng()\nf()\n";
+        let rust = "f();\nf();\n";
+        let rust_by_line = [(1, "f();"), (2, "g();"), (3, "f();")];
+
+        let line_map = LineMap::build(python, rust, trace(&rust_by_line));
+
+        let ranges = line_map
+            .entries()
+            .iter()
+            .map(|e| (e.python_line, e.rust_lines.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(1, 1..2), (3, 2..3)]);
+    }
+}
@@ -1,22 +1,37 @@
+mod config;
+mod diagnostic;
 mod error;
+mod line_map;
+mod message;
 mod subcommand;
 
-use crate::error::CliError;
-use anyhow::Result;
-use clap::{App, AppSettings, Arg};
+use crate::config::ProjectConfig;
+use crate::error::{CliError, Report};
+use clap::{App, AppSettings, Arg, ArgMatches};
 use fs_err as fs;
 use log::debug;
 
 use fs::metadata;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process;
 
 const BIN_NAME: &'static str = env!("CARGO_BIN_NAME");
 const PKG_NAME: &'static str = env!("CARGO_PKG_NAME");
 const PKG_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 const PKG_AUTHORS: &'static str = env!("CARGO_PKG_AUTHORS");
 const PKG_DESCRIPTION: &'static str = env!("CARGO_PKG_DESCRIPTION");
+/// The version of the serpent library, as resolved in Cargo.lock
+const SERPENT_VERSION: &'static str = env!("SERPENT_VERSION");
 
-fn main() -> Result<()> {
+fn main() {
+    if let Err(e) = run() {
+        eprint!("{}", Report(&e));
+        process::exit(e.exit_code() as i32);
+    }
+}
+
+fn run() -> Result<(), CliError> {
     let matches = App::new(PKG_NAME)
         .version(PKG_VERSION)
         .author(PKG_AUTHORS)
@@ -33,10 +48,25 @@ fn main() -> Result<()> {
                 .multiple(true)
                 .help("Sets the level of verbosity"),
         )
+        .subcommand(subcommand::config::app())
+        .subcommand(subcommand::diff::app())
+        .subcommand(subcommand::explain::app())
+        .subcommand(subcommand::init::app())
+        .subcommand(subcommand::report::app())
         .subcommand(subcommand::steps::app())
         .subcommand(subcommand::tp::app())
         .setting(AppSettings::SubcommandRequiredElseHelp)
-        .get_matches();
+        .get_matches_safe();
+    let matches = match matches {
+        Ok(matches) => matches,
+        // Help and version are printed into stdout and exit successfully
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => return Err(CliError::Usage(e)),
+    };
+
+    // Find the project configuration before setting up the logger, because it
+    // may set the log level
+    let project = ProjectConfig::discover(input_of(&matches))?;
 
     let (mod_loglevel, all_loglevel) = match matches.occurrences_of("q") {
         1 => (log::LevelFilter::Error, log::LevelFilter::Error),
@@ -46,8 +76,11 @@ fn main() -> Result<()> {
         // (i.e. 'myprog -v -v -v' or 'myprog -vvv' vs 'myprog -v'
         {
             match matches.occurrences_of("v") {
-                // Default is "info here", "error everywhere".
-                0 => (log::LevelFilter::Info, log::LevelFilter::Error),
+                // Default is "info here", "error everywhere", unless set in the
+                // project configuration.
+                0 => project
+                    .log_level()?
+                    .unwrap_or((log::LevelFilter::Info, log::LevelFilter::Error)),
                 // "-v" is "debug here", "warn everywhere".
                 1 => (log::LevelFilter::Debug, log::LevelFilter::Warn),
                 // "-vv" is "debug here", "info everywhere".
@@ -79,29 +112,61 @@ fn main() -> Result<()> {
 
     // Run subcommands
     if let Some(matches) = matches.subcommand_matches(subcommand::steps::name()) {
-        subcommand::steps::run(&matches)?;
+        subcommand::steps::run(&matches, &project)?;
     }
 
     if let Some(matches) = matches.subcommand_matches(subcommand::tp::name()) {
-        subcommand::tp::run(&matches)?;
+        subcommand::tp::run(&matches, &project)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches(subcommand::report::name()) {
+        subcommand::report::run(&matches)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches(subcommand::init::name()) {
+        subcommand::init::run(&matches)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches(subcommand::diff::name()) {
+        subcommand::diff::run(&matches, &project)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches(subcommand::explain::name()) {
+        subcommand::explain::run(&matches, &project)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches(subcommand::config::name()) {
+        subcommand::config::run(&matches, &project)?;
     }
 
     Ok(())
 }
 
+/// Returns the INPUT of the innermost subcommand, if any.
+fn input_of<'a>(matches: &'a ArgMatches) -> Option<&'a str> {
+    let mut matches = matches;
+    while let (_, Some(sub_matches)) = matches.subcommand() {
+        matches = sub_matches;
+    }
+    matches.value_of("INPUT")
+}
+
 /// Represents something that can be the input or an output of a transpilation
 /// process eg. a directory / module, file or a string.
 #[derive(Debug, Clone)]
 pub enum TranspileUnit {
     File(PathBuf),
     Module(PathBuf),
+    /// Python source held in memory, eg. read from stdin
+    Source(String),
 }
 
 impl TranspileUnit {
-    pub fn path(&self) -> &PathBuf {
+    pub fn path(&self) -> Option<&PathBuf> {
         match self {
-            TranspileUnit::File(path) => &path,
-            TranspileUnit::Module(path) => &path,
+            TranspileUnit::File(path) => Some(&path),
+            TranspileUnit::Module(path) => Some(&path),
+            TranspileUnit::Source(_) => None,
         }
     }
 
@@ -109,13 +174,21 @@ impl TranspileUnit {
         match self {
             TranspileUnit::File(_) => false,
             TranspileUnit::Module(_) => true,
+            TranspileUnit::Source(_) => false,
         }
     }
 }
 
-/// Generates a transpile target from given input. Input can be a file or a
-/// directory. Directories become module targets and files become file targets.
+/// Generates a transpile target from given input. Input can be a file, a
+/// directory or "-" for stdin. Directories become module targets, files become
+/// file targets and stdin becomes a source target.
 pub fn generate_target(input: &str) -> Result<TranspileUnit, CliError> {
+    if input == "-" {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source)?;
+        return Ok(TranspileUnit::Source(source));
+    }
+
     let path = to_path(input)?;
 
     // Unwrapping here is safe because we have verified that the file exists
@@ -130,6 +203,29 @@ pub fn generate_target(input: &str) -> Result<TranspileUnit, CliError> {
     }
 }
 
+/// Holds in-memory Python source in a temporary file for as long as it's
+/// alive, because the transpiler API reads its input from a path.
+pub struct SourceFile {
+    /// Created with a random name, so that no other process can place or
+    /// replace the file, and removed when dropped
+    file: tempfile::NamedTempFile,
+}
+
+impl SourceFile {
+    pub fn new(source: &str) -> Result<SourceFile, CliError> {
+        let mut file = tempfile::Builder::new()
+            .prefix("serpent-")
+            .suffix(".py")
+            .tempfile()?;
+        file.write_all(source.as_bytes())?;
+        Ok(SourceFile { file })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+}
+
 /// Maps the input string to an existing directory path
 pub fn to_dir_path_buf(input: &str) -> Result<PathBuf, CliError> {
     let path = to_path(input)?;
@@ -172,3 +268,19 @@ pub fn to_path(input: &str) -> Result<&Path, CliError> {
 
     Ok(path)
 }
+
+/// Recursively collects the paths of all Python files in a directory, sorted
+/// by path.
+pub fn python_files(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, CliError> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir.as_ref())? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(python_files(&path)?);
+        } else if path.extension().map_or(false, |ext| ext == "py") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
@@ -0,0 +1,151 @@
+//! Machine-readable messages for `--message-format json`. Each message is
+//! written into stdout as a JSON object on a line of its own, with the kind of
+//! the message in `reason`, eg.:
+//!
+//! ```json
+//! {"reason":"file-transpiled","input":"in/a.py","output":"out/src/a.rs","kind":"module"}
+//! ```
+use crate::config::Options;
+use crate::diagnostic::Diagnostic;
+use crate::error::CliError;
+use crate::subcommand::steps::Steps;
+use serde::Serialize;
+use serpent::output::TranspiledFileKind;
+
+use std::{path, str};
+
+/// A type alias for `Result<T, crate::error::CliError>`.
+pub type Result<T> = std::result::Result<T, CliError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    /// Free-form text through the logger
+    Human,
+    /// One JSON message per line in stdout
+    Json,
+}
+
+impl MessageFormat {
+    pub const VALUES: &'static [&'static str] = &["human", "json"];
+
+    /// Reads the format set with `message-format`. Subcommands without the
+    /// argument use human readable messages.
+    pub fn from_options(opts: &Options) -> Result<MessageFormat> {
+        match opts.value_of("message-format")? {
+            Some(format) => format.parse(),
+            None => Ok(MessageFormat::Human),
+        }
+    }
+}
+
+impl str::FromStr for MessageFormat {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            _ => Err(CliError::InvalidArgument("message-format", s.to_owned())),
+        }
+    }
+}
+
+/// Creates the `--message-format` argument.
+pub fn arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name("message-format")
+        .long("message-format")
+        .takes_value(true)
+        .possible_values(MessageFormat::VALUES)
+        .default_value("human")
+        .help("sets the format of progress messages")
+        .long_help("Sets the format of progress messages. `json` writes one JSON object per line into stdout, with the kind of the message in `reason`: file-started, file-transpiled, file-failed, manifest-written, line-traced and a final summary.")
+}
+
+/// A progress message.
+#[derive(Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Message<'a> {
+    /// Transpiling a Python file started
+    FileStarted {
+        path: &'a path::Path,
+    },
+    /// A Python file was transpiled
+    FileTranspiled {
+        input: &'a path::Path,
+        /// The Rust file written, if any
+        output: Option<&'a path::Path>,
+        /// The kind of a file of a module, see `kind_name`
+        kind: Option<&'static str>,
+        /// The Rust source, when it's not written into a file
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rust: Option<&'a str>,
+    },
+    /// A Python file failed to transpile. The path is missing when a module
+    /// fails without a file at fault.
+    FileFailed {
+        path: Option<&'a path::Path>,
+        code: &'static str,
+        message: &'a str,
+        line: Option<usize>,
+        column: Option<usize>,
+    },
+    ManifestWritten {
+        path: &'a path::Path,
+    },
+    /// The steps of transpiling a line, or a whole file without a line
+    LineTraced {
+        path: &'a path::Path,
+        line: Option<usize>,
+        #[serde(flatten)]
+        steps: Steps<'a>,
+    },
+    /// The outcome of the run
+    Summary {
+        success: bool,
+        /// Number of files transpiled or traced
+        files: usize,
+        failed: usize,
+    },
+}
+
+impl<'a> Message<'a> {
+    pub fn failed(diagnostic: &'a Diagnostic) -> Message<'a> {
+        Message::FileFailed {
+            path: diagnostic.path.as_deref(),
+            code: diagnostic.code,
+            message: &diagnostic.message,
+            line: diagnostic.line,
+            column: diagnostic.column,
+        }
+    }
+}
+
+/// Returns the name of the kind serpent gave a file of a module: "lib" for the
+/// library root, "bin" for an entry point and "module" for other files.
+pub fn kind_name(kind: &TranspiledFileKind) -> &'static str {
+    match kind {
+        TranspiledFileKind::LibRs => "lib",
+        TranspiledFileKind::MainRs => "bin",
+        TranspiledFileKind::Normal => "module",
+    }
+}
+
+/// Writes a message into stdout.
+pub fn emit(message: &Message) -> Result<()> {
+    println!("{}", serde_json::to_string(message)?);
+    Ok(())
+}
+
+/// Writes a file-failed message for each diagnostic of a failed run. Returns
+/// the number of failed files.
+pub fn emit_failures(error: &CliError) -> Result<usize> {
+    match error {
+        CliError::Diagnostics(diagnostics) => {
+            for diagnostic in diagnostics {
+                emit(&Message::failed(diagnostic))?;
+            }
+            Ok(diagnostics.len())
+        }
+        _ => Ok(0),
+    }
+}
@@ -0,0 +1,121 @@
+//! Subcommand for inspecting the project configuration.
+use crate::config::{Options, ProjectConfig};
+use crate::error::CliError;
+use crate::subcommand::tp;
+
+/// A type alias for `Result<T, crate::error::CliError>`.
+pub type Result<T> = std::result::Result<T, CliError>;
+
+/// The kind of a configuration key.
+enum Kind {
+    Flag,
+    /// A key with a value, with a description of its default value
+    Value(&'static str),
+}
+
+/// The keys that can be set in the project configuration, by section.
+const KEYS: &[(&str, &[(&str, Kind)])] = &[
+    ("", &[("log-level", Kind::Value("\"info\""))]),
+    (
+        "transpile",
+        &[
+            ("output", Kind::Value("none, prints into the terminal")),
+            ("lines", Kind::Flag),
+            ("fmt", Kind::Flag),
+            ("emit-manifest", Kind::Flag),
+            ("omit-manifest", Kind::Flag),
+            ("edition", Kind::Value("\"2018\"")),
+            ("remap-file", Kind::Value("auto-detected Remap.toml")),
+            ("python-deps", Kind::Value("none")),
+            ("no-remap", Kind::Flag),
+            ("merge", Kind::Flag),
+            ("check", Kind::Flag),
+            ("source-maps", Kind::Flag),
+            ("watch", Kind::Flag),
+            ("cache", Kind::Flag),
+            ("cache-dir", Kind::Value("OUTPUT/.serpent-cache")),
+            ("jobs", Kind::Value("number of CPUs")),
+            ("keep-going", Kind::Flag),
+            ("stats", Kind::Value("none")),
+            ("message-format", Kind::Value("\"human\"")),
+            ("sarif", Kind::Value("none")),
+        ],
+    ),
+    (
+        "steps",
+        &[
+            ("file", Kind::Value("none")),
+            ("top", Kind::Flag),
+            ("line", Kind::Value("none")),
+            ("def", Kind::Value("none")),
+            ("class", Kind::Value("none")),
+            ("format", Kind::Value("\"text\"")),
+            ("message-format", Kind::Value("\"human\"")),
+        ],
+    ),
+    ("diff", &[("format", Kind::Value("\"text\""))]),
+    ("explain", &[("format", Kind::Value("\"text\""))]),
+];
+
+/// Create the clap subcommand for `config`.
+pub fn app() -> clap::App<'static, 'static> {
+    clap::SubCommand::with_name(name())
+        .about("Inspects the project configuration in serpent.toml or in the [tool.serpent] table of pyproject.toml.")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            clap::SubCommand::with_name("show")
+                .about("Prints the effective configuration for INPUT and the source of each value. Options of `tp` given here override the configuration file.")
+                .arg(
+                    clap::Arg::with_name("INPUT")
+                        .help("sets the input module or file to find the configuration for, defaults to the current directory")
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("sets an output file or directory"),
+                )
+                .args(&tp::transpile_args()),
+        )
+}
+
+/// Run the behavior of the `config` subcommand.
+pub fn run(matches: &clap::ArgMatches, project: &ProjectConfig) -> Result<()> {
+    if let Some(matches) = matches.subcommand_matches("show") {
+        show(matches, project)?;
+    }
+    Ok(())
+}
+
+pub fn name() -> &'static str {
+    "config"
+}
+
+/// Prints the effective value and the source of each key.
+fn show(matches: &clap::ArgMatches, project: &ProjectConfig) -> Result<()> {
+    match project.path() {
+        Some(path) => println!("# Configuration file: {}", path.display()),
+        None => println!("# No configuration file found"),
+    }
+
+    for (section, keys) in KEYS {
+        let opts = Options::new(matches, project, *section);
+        if !section.is_empty() {
+            println!("\n[{}]", section);
+        }
+        for (key, kind) in keys.iter() {
+            let value = match kind {
+                Kind::Flag => opts.is_present(key)?.to_string(),
+                Kind::Value(default) => match opts.value_of(key)? {
+                    Some(value) => format!("{:?}", value),
+                    None => default.to_string(),
+                },
+            };
+            println!("{} = {}  ({})", key, value, opts.source_of(key));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,226 @@
+//! Subcommand for comparing freshly transpiled output against an existing
+//! output directory.
+use log::info;
+
+use crate::config::{Options, ProjectConfig};
+use crate::error::CliError;
+use crate::subcommand::tp;
+use fs_err as fs;
+
+use std::path;
+
+/// A type alias for `Result<T, crate::error::CliError>`.
+pub type Result<T> = std::result::Result<T, CliError>;
+
+/// Create the clap subcommand for `diff`.
+pub fn app() -> clap::App<'static, 'static> {
+    clap::SubCommand::with_name(name())
+        .about("Transpiles INPUT module in memory and compares the result against OUTDIR.")
+        .arg(
+            clap::Arg::with_name("INPUT")
+                .help("sets the input module to transpile")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            clap::Arg::with_name("output")
+                .value_name("OUTDIR")
+                .help("sets the output directory to compare against")
+                .required(true)
+                .index(2),
+        )
+        .args(&tp::transpile_args())
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "github"])
+                .default_value("text")
+                .help("sets the output format")
+                .long_help("Sets the output format. \"text\" prints unified diffs, \"github\" renders them as Markdown for pull request comments."),
+        )
+}
+
+/// Run the behavior of the `diff` subcommand.
+pub fn run(matches: &clap::ArgMatches, project: &ProjectConfig) -> Result<()> {
+    let cfg = resolve_args(matches, project)?;
+    do_work(&cfg)
+}
+
+fn resolve_args(matches: &clap::ArgMatches, project: &ProjectConfig) -> Result<Config> {
+    // Calling .unwrap() is safe here because "INPUT" and "output" are required
+    let input = matches.value_of("INPUT").unwrap();
+    if input == "-" {
+        return Err(CliError::StdinNotSupported(name()));
+    }
+    // Verify that the input is a module before transpiling
+    crate::to_dir_path_buf(input)?;
+    let out_dir = path::PathBuf::from(matches.value_of("output").unwrap());
+
+    let opts = Options::new(matches, project, name());
+    // Calling .unwrap() is safe here because "format" has a default value
+    let format = match opts.value_of("format")?.unwrap().as_str() {
+        "github" => Format::Github,
+        "text" => Format::Text,
+        format => return Err(CliError::InvalidArgument("format", format.to_owned())),
+    };
+
+    Ok(Config {
+        tp_cfg: tp::resolve_transpile_args(matches, project, &out_dir)?,
+        out_dir,
+        format,
+    })
+}
+
+pub fn name() -> &'static str {
+    "diff"
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    /// Markdown for GitHub pull request comments
+    Github,
+}
+
+struct Config {
+    tp_cfg: tp::Config,
+    /// The output directory to compare against
+    out_dir: path::PathBuf,
+    format: Format,
+}
+
+/// A difference between the generated output and the output directory.
+enum Change {
+    /// The file would be created
+    Added(String),
+    /// The file exists but would not be generated
+    Removed(String),
+    /// The file would be changed, with a unified diff of the change
+    Modified(String, String),
+}
+
+fn do_work(cfg: &Config) -> Result<()> {
+    let generated = tp::generate_module(&cfg.tp_cfg)?;
+
+    let mut expected = generated
+        .files
+        .iter()
+        .map(|(path, contents)| (slash_path(path), contents.as_str()))
+        .collect::<Vec<_>>();
+    if let Some(manifest) = &generated.manifest {
+        expected.push(("Cargo.toml".to_owned(), manifest.as_str()));
+    }
+
+    let mut changes = vec![];
+    for (name, contents) in &expected {
+        let path = cfg.out_dir.join(name);
+        if !path.is_file() {
+            changes.push(Change::Added(name.clone()));
+            continue;
+        }
+        let on_disk = fs::read_to_string(&path)?;
+        if on_disk != *contents {
+            changes.push(Change::Modified(
+                name.clone(),
+                unified_diff(name, &on_disk, contents),
+            ));
+        }
+    }
+
+    // Rust files in the output directory that would no longer be generated
+    let src_dir = cfg.out_dir.join("src");
+    if src_dir.is_dir() {
+        for path in rust_files(&src_dir)? {
+            // Unwrap is safe because `rust_files` only returns files within `src_dir`
+            let name = slash_path(path.strip_prefix(&cfg.out_dir).unwrap());
+            if !expected.iter().any(|(expected, _)| *expected == name) {
+                changes.push(Change::Removed(name));
+            }
+        }
+    }
+
+    if changes.is_empty() {
+        info!(
+            "{:?} is up to date with {} file(s)",
+            cfg.out_dir,
+            expected.len()
+        );
+        return Ok(());
+    }
+
+    let report = match cfg.format {
+        Format::Text => text_report(&changes),
+        Format::Github => github_report(&changes),
+    };
+    print!("{}", report);
+
+    Err(CliError::OutputDiffers(changes.len()))
+}
+
+/// Renders a unified diff from the contents on disk to the generated contents.
+fn unified_diff(name: &str, old: &str, new: &str) -> String {
+    let patch = diffy::create_patch(old, new).to_string();
+    // Replace the generic "original" and "modified" headers with the file name
+    let hunks = patch.splitn(3, '\n').nth(2).unwrap_or_default();
+    format!("--- a/{0}\n+++ b/{0}\n{1}", name, hunks)
+}
+
+fn text_report(changes: &[Change]) -> String {
+    let mut out = String::new();
+    for change in changes {
+        match change {
+            Change::Added(name) => out.push_str(&format!("added: {}\n", name)),
+            Change::Removed(name) => out.push_str(&format!("removed: {}\n", name)),
+            Change::Modified(_, diff) => out.push_str(diff),
+        }
+    }
+    out
+}
+
+fn github_report(changes: &[Change]) -> String {
+    let mut out = format!(
+        "### serpent output differs\n\n{} file(s) differ from the transpiled output.\n\n",
+        changes.len()
+    );
+    for change in changes {
+        match change {
+            Change::Added(name) => out.push_str(&format!("- **added** `{}`\n", name)),
+            Change::Removed(name) => out.push_str(&format!("- **removed** `{}`\n", name)),
+            Change::Modified(name, _) => out.push_str(&format!("- **modified** `{}`\n", name)),
+        }
+    }
+    for change in changes {
+        if let Change::Modified(name, diff) = change {
+            out.push_str(&format!(
+                "\n<details>\n<summary><code>{}</code></summary>\n\n```diff\n{}```\n\n</details>\n",
+                name, diff
+            ));
+        }
+    }
+    out
+}
+
+/// Returns the Rust files in `dir` and its subdirectories in a stable order.
+fn rust_files(dir: &path::Path) -> Result<Vec<path::PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(rust_files(&path)?);
+        } else if path.extension().map_or(false, |ext| ext == "rs") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Formats a relative path with forward slashes for stable output across
+/// platforms.
+fn slash_path(path: &path::Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,213 @@
+//! Subcommand for finding the Python line that produced a line of transpiled
+//! Rust, eg. to make sense of a rustc or clippy diagnostic.
+use log::info;
+use serde::Serialize;
+
+use crate::config::{Options, ProjectConfig};
+use crate::error::CliError;
+use crate::subcommand::{steps::Steps, tp};
+
+use std::path::{Path, PathBuf};
+
+/// A type alias for `Result<T, crate::error::CliError>`.
+pub type Result<T> = std::result::Result<T, CliError>;
+
+/// Create the clap subcommand for `explain`.
+pub fn app() -> clap::App<'static, 'static> {
+    clap::SubCommand::with_name(name())
+        .about("Finds the Python line that produced LOCATION in the output of INPUT module and shows its transpilation steps.")
+        .arg(
+            clap::Arg::with_name("LOCATION")
+                .help("sets the Rust location to explain, as FILE.rs:LINE")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            clap::Arg::with_name("INPUT")
+                .long("input")
+                .short("i")
+                .takes_value(true)
+                .required(true)
+                .help("sets the input module that was transpiled"),
+        )
+        .args(&tp::transpile_args())
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("sets the output format"),
+        )
+}
+
+/// Run the behavior of the `explain` subcommand.
+pub fn run(matches: &clap::ArgMatches, project: &ProjectConfig) -> Result<()> {
+    let cfg = resolve_args(matches, project)?;
+    do_work(&cfg)
+}
+
+fn resolve_args(matches: &clap::ArgMatches, project: &ProjectConfig) -> Result<Config> {
+    // Calling .unwrap() is safe here because "LOCATION" and "INPUT" are required
+    let location = matches.value_of("LOCATION").unwrap();
+    let (rust_path, rust_line) = parse_location(location)?;
+    let mod_out_path = crate_root(&rust_path)?;
+
+    let input = matches.value_of("INPUT").unwrap();
+    if input == "-" {
+        return Err(CliError::StdinNotSupported(name()));
+    }
+    // Verify that the input is a module before transpiling
+    crate::to_dir_path_buf(input)?;
+
+    let opts = Options::new(matches, project, name());
+    // Calling .unwrap() is safe here because "format" has a default value
+    let format = match opts.value_of("format")?.unwrap().as_str() {
+        "json" => Format::Json,
+        "text" => Format::Text,
+        format => return Err(CliError::InvalidArgument("format", format.to_owned())),
+    };
+
+    Ok(Config {
+        tp_cfg: tp::resolve_transpile_args(matches, project, &mod_out_path)?,
+        mod_out_path,
+        rust_path,
+        rust_line,
+        format,
+    })
+}
+
+pub fn name() -> &'static str {
+    "explain"
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+struct Config {
+    tp_cfg: tp::Config,
+    /// The output directory containing `rust_path`
+    mod_out_path: PathBuf,
+    rust_path: PathBuf,
+    rust_line: usize,
+    format: Format,
+}
+
+#[derive(Serialize)]
+struct Explanation<'t> {
+    rust_path: &'t Path,
+    rust_line: usize,
+    python_path: &'t Path,
+    python_line: usize,
+    #[serde(flatten)]
+    steps: Steps<'t>,
+}
+
+/// Parses a location such as "out/src/pricing.rs:142".
+fn parse_location(location: &str) -> Result<(PathBuf, usize)> {
+    let mut parts = location.rsplitn(2, ':');
+    let line = parts.next().and_then(|line| line.parse::<usize>().ok());
+    match (parts.next(), line) {
+        (Some(path), Some(line)) if !path.is_empty() && line > 0 => Ok((PathBuf::from(path), line)),
+        _ => Err(CliError::InvalidArgument("LOCATION", location.to_owned())),
+    }
+}
+
+/// Returns the output directory of a transpiled Rust file, which is the parent
+/// of the closest "src" directory, eg. "out" for "out/src/bin/run.rs".
+fn crate_root(rust_path: &Path) -> Result<PathBuf> {
+    rust_path
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.file_name().map_or(false, |name| name == "src"))
+        .and_then(Path::parent)
+        .map(Path::to_path_buf)
+        .ok_or_else(|| CliError::NotInModule(rust_path.to_path_buf()))
+}
+
+fn do_work(cfg: &Config) -> Result<()> {
+    let explanation = tp::explain(
+        &cfg.tp_cfg,
+        &cfg.mod_out_path,
+        &cfg.rust_path,
+        cfg.rust_line,
+    )?;
+    let steps = Steps::new(&explanation.trace);
+
+    match cfg.format {
+        Format::Text => {
+            info!(
+                "{}:{} was produced by {}:{}\n",
+                cfg.rust_path.display(),
+                cfg.rust_line,
+                explanation.python_path.display(),
+                explanation.python_line
+            );
+            steps.log();
+        }
+        Format::Json => {
+            let explanation = Explanation {
+                rust_path: &cfg.rust_path,
+                rust_line: cfg.rust_line,
+                python_path: &explanation.python_path,
+                python_line: explanation.python_line,
+                steps,
+            };
+            println!("{}", serde_json::to_string(&explanation)?);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_location_reads_path_and_line() {
+        assert_eq!(
+            parse_location("out/src/pricing.rs:142").unwrap(),
+            (PathBuf::from("out/src/pricing.rs"), 142)
+        );
+        // The drive letter of a Windows path is part of the path
+        assert_eq!(
+            parse_location(r"C:\out\src\pricing.rs:12").unwrap(),
+            (PathBuf::from(r"C:\out\src\pricing.rs"), 12)
+        );
+    }
+
+    #[test]
+    fn parse_location_rejects_missing_parts() {
+        for location in &[
+            "out/src/pricing.rs",
+            "out/src/pricing.rs:",
+            ":12",
+            "a.rs:0",
+            "a.rs:x",
+        ] {
+            assert!(
+                parse_location(location).is_err(),
+                "{:?} should be rejected",
+                location
+            );
+        }
+    }
+
+    #[test]
+    fn crate_root_is_the_parent_of_the_closest_src() {
+        assert_eq!(
+            crate_root(Path::new("out/src/bin/run.rs")).unwrap(),
+            PathBuf::from("out")
+        );
+        assert_eq!(
+            crate_root(Path::new("work/src/out/src/lib.rs")).unwrap(),
+            PathBuf::from("work/src/out")
+        );
+        assert!(crate_root(Path::new("out/lib.rs")).is_err());
+        assert!(crate_root(Path::new("src")).is_err());
+    }
+}
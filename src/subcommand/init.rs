@@ -0,0 +1,225 @@
+//! Subcommand for scaffolding a Remap.toml from the imports of a module.
+use itertools::Itertools;
+use log::{debug, info};
+
+use crate::subcommand::tp::write_file;
+use crate::{error::CliError, generate_target, python_files, TranspileUnit};
+use fs_err as fs;
+
+use std::{collections::BTreeMap, path};
+
+/// A type alias for `Result<T, crate::error::CliError>`.
+pub type Result<T> = std::result::Result<T, CliError>;
+
+/// Modules of the Python standard library that are never listed as third-party
+/// imports.
+#[rustfmt::skip]
+const STDLIB_MODULES: &[&str] = &[
+    "__future__", "abc", "argparse", "array", "ast", "asyncio", "base64", "bisect", "builtins",
+    "calendar", "cmath", "collections", "concurrent", "configparser", "contextlib", "copy",
+    "csv", "ctypes", "dataclasses", "datetime", "decimal", "difflib", "enum", "errno",
+    "fractions", "functools", "gc", "getopt", "glob", "gzip", "hashlib", "heapq", "hmac",
+    "html", "http", "importlib", "inspect", "io", "itertools", "json", "logging", "math",
+    "multiprocessing", "operator", "os", "pathlib", "pickle", "platform", "pprint", "queue",
+    "random", "re", "secrets", "select", "shutil", "signal", "socket", "sqlite3", "statistics",
+    "string", "struct", "subprocess", "sys", "tempfile", "textwrap", "threading", "time",
+    "timeit", "traceback", "types", "typing", "unittest", "urllib", "uuid", "warnings",
+    "weakref", "xml", "zipfile", "zlib",
+];
+
+/// Create the clap subcommand for `init`.
+pub fn app() -> clap::App<'static, 'static> {
+    clap::SubCommand::with_name(name())
+        .about("Writes a Remap.toml skeleton listing the third-party imports of INPUT, which is a module or a file.")
+        .arg(
+            clap::Arg::with_name("INPUT")
+                .help("sets the input module or file to scan for imports")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            clap::Arg::with_name("force")
+                .long("force")
+                .help("overwrites an existing Remap.toml"),
+        )
+}
+
+/// Run the behavior of the `init` subcommand.
+pub fn run(matches: &clap::ArgMatches) -> Result<()> {
+    let cfg = resolve_args(matches)?;
+    do_work(&cfg)
+}
+
+fn resolve_args(matches: &clap::ArgMatches) -> Result<Config> {
+    // Calling .unwrap() is safe here because "INPUT" is required
+    let input = matches.value_of("INPUT").unwrap();
+    let target = generate_target(input)?;
+
+    // Place the remap file where `tp` auto-detects it from
+    let (module_dir, files) = match &target {
+        TranspileUnit::File(path) => {
+            let dir = path
+                .parent()
+                .unwrap_or_else(|| path::Path::new(""))
+                .to_path_buf();
+            (dir, vec![path.clone()])
+        }
+        TranspileUnit::Module(path) => (path.clone(), python_files(path)?),
+        TranspileUnit::Source(_) => return Err(CliError::StdinNotSupported(name())),
+    };
+    let remap_file = module_dir.join("Remap.toml");
+
+    Ok(Config {
+        module_dir,
+        files,
+        remap_file,
+        force: matches.is_present("force"),
+    })
+}
+
+pub fn name() -> &'static str {
+    "init"
+}
+
+struct Config {
+    module_dir: path::PathBuf,
+    /// The Python files to scan for imports
+    files: Vec<path::PathBuf>,
+    remap_file: path::PathBuf,
+    /// Should overwrite an existing remap file?
+    force: bool,
+}
+
+fn do_work(cfg: &Config) -> Result<()> {
+    if cfg.remap_file.exists() && !cfg.force {
+        return Err(CliError::FileExists(cfg.remap_file.clone()));
+    }
+
+    // Map each imported top-level module to the files that import it
+    let mut imports = BTreeMap::<String, Vec<String>>::new();
+    for file in &cfg.files {
+        let source = fs::read_to_string(file)?;
+        let file_name = file
+            .strip_prefix(&cfg.module_dir)
+            .unwrap_or(file)
+            .display()
+            .to_string();
+        for module in imported_modules(&source) {
+            let importers = imports.entry(module).or_default();
+            if !importers.contains(&file_name) {
+                importers.push(file_name.clone());
+            }
+        }
+    }
+
+    let local_modules = local_modules(&cfg.module_dir)?;
+    let imports = imports
+        .into_iter()
+        .filter(|(module, _)| {
+            let third_party =
+                !STDLIB_MODULES.contains(&module.as_str()) && !local_modules.contains(module);
+            if !third_party {
+                debug!("Skipping standard library or local module {:?}", module);
+            }
+            third_party
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    info!(
+        "Writing {:?} with {} third-party module(s)",
+        &cfg.remap_file,
+        imports.len()
+    );
+    write_file(&cfg.remap_file, &skeleton(&imports))
+}
+
+/// Returns the top-level module names imported by absolute `import` and
+/// `from ... import` statements.
+fn imported_modules(source: &str) -> Vec<String> {
+    let top_level = |module: &str| module.split('.').next().unwrap_or_default().to_owned();
+
+    let mut modules = vec![];
+    for line in source.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("import ") {
+            // import a.b as c, d
+            modules.extend(
+                rest.split(',')
+                    .filter_map(|item| item.split_whitespace().next())
+                    .map(top_level),
+            );
+        } else if let Some(rest) = line.strip_prefix("from ") {
+            // from a.b import c, but not relative imports like `from . import c`
+            if let Some(module) = rest.split_whitespace().next() {
+                if !module.starts_with('.') {
+                    modules.push(top_level(module));
+                }
+            }
+        }
+    }
+    modules.retain(|m| !m.is_empty());
+    modules
+}
+
+/// Returns the names that resolve to files or packages within the module
+/// itself.
+fn local_modules(module_dir: &path::Path) -> Result<Vec<String>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(module_dir)? {
+        let path = entry?.path();
+        let is_module = path.is_dir() || path.extension().map_or(false, |ext| ext == "py");
+        if let (true, Some(stem)) = (is_module, path.file_stem()) {
+            names.push(stem.to_string_lossy().into_owned());
+        }
+    }
+    Ok(names)
+}
+
+/// Renders a commented Remap.toml skeleton for the given third-party modules.
+fn skeleton(imports: &BTreeMap<String, Vec<String>>) -> String {
+    let mut out = String::from(
+        "# Remap.toml generated by `serpent init`.\n\
+         #\n\
+         # List the crates required by the transpiled code in [dependencies], eg.\n\
+         # ndarray = \"0.15\"\n\
+         [dependencies]\n",
+    );
+
+    for (module, importers) in imports {
+        out.push_str(&format!(
+            "\n# `{}` is imported in: {}\n# [{}]\n# \"{}.<name>\" = \"<rust path>\"\n",
+            module,
+            importers.iter().join(", "),
+            module,
+            module
+        ));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imported_modules_reads_top_level_modules() {
+        let source = "\
+import os, numpy.linalg as la
+import scipy.stats
+from pandas.core import frame
+    from yaml import safe_load
+";
+
+        assert_eq!(
+            imported_modules(source),
+            vec!["os", "numpy", "scipy", "pandas", "yaml"]
+        );
+    }
+
+    #[test]
+    fn imported_modules_skips_relative_imports() {
+        let source = "from . import sibling\nfrom .pricing import price\nfrom ..util import f\n";
+
+        assert!(imported_modules(source).is_empty());
+    }
+}
@@ -1,2 +1,7 @@
+pub mod config;
+pub mod diff;
+pub mod explain;
+pub mod init;
+pub mod report;
 pub mod steps;
 pub mod tp;
@@ -0,0 +1,241 @@
+//! Subcommand for writing a side-by-side HTML report of a transpilation.
+use itertools::Itertools;
+use log::info;
+use serpent::{TranspileConfig, TranspileModuleBuilder, TranspiledFile};
+
+use crate::subcommand::tp::{self, write_file};
+use crate::{diagnostic, error::CliError, generate_target, line_map::LineMap, TranspileUnit};
+use fs_err as fs;
+
+use std::path;
+
+/// A type alias for `Result<T, crate::error::CliError>`.
+pub type Result<T> = std::result::Result<T, CliError>;
+
+/// Create the clap subcommand for `report`.
+pub fn app() -> clap::App<'static, 'static> {
+    clap::SubCommand::with_name(name())
+        .about(
+            "Writes a side-by-side HTML report of transpiling INPUT, which is a module or a file.",
+        )
+        .arg(
+            clap::Arg::with_name("INPUT")
+                .help("sets the input module or file to transpile")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            clap::Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .required(true)
+                .help("sets the directory to write the report into"),
+        )
+}
+
+/// Run the behavior of the `report` subcommand.
+pub fn run(matches: &clap::ArgMatches) -> Result<()> {
+    let cfg = resolve_args(matches)?;
+    do_work(&cfg)
+}
+
+fn resolve_args(matches: &clap::ArgMatches) -> Result<Config> {
+    // Calling .unwrap() is safe here because "INPUT" and "output" are required
+    let input = matches.value_of("INPUT").unwrap();
+    let transpile_target = generate_target(input)?;
+    let output = path::PathBuf::from(matches.value_of("output").unwrap());
+
+    Ok(Config {
+        transpile_target,
+        output,
+    })
+}
+
+pub fn name() -> &'static str {
+    "report"
+}
+
+struct Config {
+    transpile_target: TranspileUnit,
+    /// The directory to write the report into
+    output: path::PathBuf,
+}
+
+fn do_work(cfg: &Config) -> Result<()> {
+    fs::create_dir_all(&cfg.output)?;
+
+    let pages = match &cfg.transpile_target {
+        TranspileUnit::File(path) => {
+            let transpiled = serpent::transpile_file(&path)
+                .map_err(|e| diagnostic::file_error(path, None, e))?;
+            let root = path.parent().unwrap_or_else(|| path::Path::new(""));
+            vec![write_file_page(root, &transpiled, &cfg.output)?]
+        }
+        TranspileUnit::Module(path) => {
+            let transpiled = serpent::transpile_module(&path).map_err(|e| {
+                tp::module_error(path, e, |path| {
+                    TranspileModuleBuilder::new(path).config(TranspileConfig::default())
+                })
+            })?;
+            transpiled
+                .files()
+                .iter()
+                .map(|file| write_file_page(path, file, &cfg.output))
+                .collect::<Result<Vec<_>>>()?
+        }
+        TranspileUnit::Source(_) => return Err(CliError::StdinNotSupported(name())),
+    };
+
+    let index_path = cfg.output.join("index.html");
+    info!("Writing report index into {:?}", index_path);
+    write_file(index_path, &index_page(&pages))
+}
+
+/// Writes the report page for `file` into `out_dir`. Returns the title and the
+/// file name of the page.
+fn write_file_page(
+    root: &path::Path,
+    file: &TranspiledFile,
+    out_dir: &path::Path,
+) -> Result<(String, String)> {
+    let source_path = file.source_path();
+    let title = source_path
+        .strip_prefix(root)
+        .unwrap_or(source_path)
+        .display()
+        .to_string();
+    let page_name = format!("{}.html", title.replace(|c| c == '/' || c == '\\', "."));
+
+    let page_path = out_dir.join(&page_name);
+    info!("Writing report for {:?} into {:?}", source_path, page_path);
+    write_file(&page_path, &file_page(&title, file)?)?;
+
+    Ok((title, page_name))
+}
+
+/// Renders the page listing all transpiled files.
+fn index_page(pages: &[(String, String)]) -> String {
+    let items = pages
+        .iter()
+        .map(|(title, page)| {
+            format!(
+                "<li><a href=\"{}\">{}</a></li>",
+                escape(page),
+                escape(title)
+            )
+        })
+        .join("\n");
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>serpent report</title>\n<style>{}</style>\n</head>\n<body>\n<h1>serpent report</h1>\n<ul>\n{}\n</ul>\n</body>\n</html>\n",
+        STYLE, items
+    )
+}
+
+/// Renders the page with the Python source and the Rust output side by side.
+fn file_page(title: &str, file: &TranspiledFile) -> Result<String> {
+    let python_source = fs::read_to_string(file.source_path())?;
+    let rust_target = &file.content().rust_target;
+    let line_map = LineMap::build(&python_source, rust_target, |line| {
+        file.trace_steps_for_line(line, false)
+    });
+
+    let python_lines = python_source
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            let line_no = idx + 1;
+            let link = match line_map.entries().iter().find(|e| e.python_line == line_no) {
+                Some(e) => format!(
+                    " data-start=\"{}\" data-end=\"{}\"",
+                    e.rust_lines.start, e.rust_lines.end
+                ),
+                None => String::new(),
+            };
+            format!(
+                "<tr id=\"py-{0}\" class=\"py\"{1}><td class=\"no\">{0}</td><td><pre>{2}</pre></td></tr>",
+                line_no,
+                link,
+                escape(line)
+            )
+        })
+        .join("\n");
+
+    let rust_lines = rust_target
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            let line_no = idx + 1;
+            let link = match line_map.python_line_for(line_no) {
+                Some(py_line) => format!(" data-py=\"{}\"", py_line),
+                None => String::new(),
+            };
+            format!(
+                "<tr id=\"rs-{0}\" class=\"rs\"{1}><td class=\"no\">{0}</td><td><pre>{2}</pre></td></tr>",
+                line_no,
+                link,
+                escape(line)
+            )
+        })
+        .join("\n");
+
+    Ok(format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n<style>{1}</style>\n</head>\n<body>\n<p><a href=\"index.html\">index</a></p>\n<h1>{0}</h1>\n<div class=\"columns\">\n<table>\n{2}\n</table>\n<table>\n{3}\n</table>\n</div>\n<script>{4}</script>\n</body>\n</html>\n",
+        escape(title),
+        STYLE,
+        python_lines,
+        rust_lines,
+        SCRIPT
+    ))
+}
+
+/// Escapes text for embedding in HTML.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 1em; }
+.columns { display: flex; gap: 1em; align-items: flex-start; }
+table { flex: 1; border-collapse: collapse; font-size: 0.9em; }
+td { padding: 0 0.5em; vertical-align: top; }
+td pre { margin: 0; font-family: monospace; white-space: pre-wrap; }
+td.no { color: #888; text-align: right; user-select: none; }
+tr[data-start], tr[data-py] { cursor: pointer; }
+tr.hl { background: #fff3b0; }
+";
+
+/// Highlights the Rust lines produced by a clicked Python line and the Python
+/// line that produced a clicked Rust line.
+const SCRIPT: &str = "
+function clear() {
+  document.querySelectorAll('tr.hl').forEach(function (tr) { tr.classList.remove('hl'); });
+}
+function highlightPython(tr) {
+  clear();
+  tr.classList.add('hl');
+  var start = parseInt(tr.dataset.start), end = parseInt(tr.dataset.end);
+  for (var i = start; i < end; i++) {
+    var rs = document.getElementById('rs-' + i);
+    if (rs) { rs.classList.add('hl'); }
+  }
+  var first = document.getElementById('rs-' + start);
+  if (first) { first.scrollIntoView({ block: 'center' }); }
+}
+document.querySelectorAll('tr.py[data-start]').forEach(function (tr) {
+  tr.addEventListener('click', function () { highlightPython(tr); });
+});
+document.querySelectorAll('tr.rs[data-py]').forEach(function (tr) {
+  tr.addEventListener('click', function () {
+    var py = document.getElementById('py-' + tr.dataset.py);
+    if (py && py.dataset.start) {
+      highlightPython(py);
+      py.scrollIntoView({ block: 'center' });
+    }
+  });
+});
+";
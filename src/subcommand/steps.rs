@@ -1,10 +1,18 @@
 //! Subcommand for showing the intermediate steps in a transpilation operation.
 use log::info;
+use serde::Serialize;
+use serpent::{TranspileConfig, TranspileModuleBuilder, TranspiledFile};
 
+use crate::config::{Options, ProjectConfig};
+use crate::diagnostic;
+use crate::message::{self, Message, MessageFormat};
+use crate::subcommand::tp;
 use crate::{error::CliError, to_file_path_buf};
-use crate::{generate_target, TranspileUnit};
+use crate::{generate_target, SourceFile, TranspileUnit};
+use fs_err as fs;
 
-use std::path::PathBuf;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
 /// A type alias for `Result<T, crate::error::CliError>`.
 pub type Result<T> = std::result::Result<T, CliError>;
@@ -12,7 +20,7 @@ pub type Result<T> = std::result::Result<T, CliError>;
 /// Create the clap subcommand for `steps`.
 pub fn app() -> clap::App<'static, 'static> {
     clap::SubCommand::with_name(name())
-        .about("Shows transpilation steps for given INPUT, which is a module or a file.")
+        .about("Shows transpilation steps for given INPUT, which is a module, a file or \"-\" for stdin.")
         .arg(
             clap::Arg::with_name("INPUT")
                 .help("Sets the input file or module to use")
@@ -34,42 +42,96 @@ pub fn app() -> clap::App<'static, 'static> {
         .arg(
             clap::Arg::with_name("line")
                 .short("l")
+                .long("line")
                 .takes_value(true)
-                .help("show steps for this line")
-                .required_unless_one(&["top"]),
+                .multiple(true)
+                .number_of_values(1)
+                .help("show steps for this line or range of lines, eg. 10 or 10..25")
+                .long_help("Shows steps for a line, eg. 10, an inclusive range of lines, eg. 10..25, or the lines from a line to the end of the file, eg. `10..`. Ranges past the end of the file are clipped. Can be repeated."),
         )
+        .arg(
+            clap::Arg::with_name("def")
+                .long("def")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("show steps for every line of the function with this name"),
+        )
+        .arg(
+            clap::Arg::with_name("class")
+                .long("class")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("show steps for every line of the class with this name"),
+        )
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("sets the output format")
+                .long_help("Sets the output format. `json` prints one JSON object per traced file and line into stdout, with the fields `path`, `line`, `python_source`, `python_ast`, `rust_ast` and `rust_source`."),
+        )
+        .arg(message::arg().long_help("Sets the format of progress messages. `json` writes one JSON object per line into stdout, with the kind of the message in `reason`: file-started, line-traced, file-failed and a final summary. Overrides --format."))
 }
 
 /// Run the behavior of the `steps` subcommand.
-pub fn run(matches: &clap::ArgMatches) -> Result<()> {
-    let cfg = resolve_args(matches)?;
+pub fn run(matches: &clap::ArgMatches, project: &ProjectConfig) -> Result<()> {
+    let cfg = resolve_args(matches, project)?;
     do_work(&cfg)
 }
 
-fn resolve_args(matches: &clap::ArgMatches) -> Result<Config> {
+fn resolve_args(matches: &clap::ArgMatches, project: &ProjectConfig) -> Result<Config> {
+    // Options not given on the command line default to the project configuration
+    let opts = Options::new(matches, project, name());
+
     // Calling .unwrap() is safe here because "INPUT" is required
     let input = matches.value_of("INPUT").unwrap();
     let transpile_target = generate_target(input)?;
 
-    let explicit_target_file = matches
-        .value_of("file")
+    let explicit_target_file = opts
+        .value_of("file")?
+        .as_deref()
         .map(to_file_path_buf)
         .map_or(Ok(None), |v| v.map(Some))?;
 
-    let top_only = matches.is_present("top");
+    let top_only = opts.is_present("top")?;
 
-    let line = matches.value_of("line").map(|line| {
-        line.parse::<usize>()
-            .expect(&format!("cannot parse usize from {}", line))
-    });
+    let selection = Selection {
+        ranges: opts
+            .values_of("line")?
+            .iter()
+            .map(|line| parse_line_range(line))
+            .collect::<Result<_>>()?,
+        defs: opts.values_of("def")?,
+        classes: opts.values_of("class")?,
+    };
+
+    // Calling .unwrap() is safe here because "format" has a default value
+    let format = match opts.value_of("format")?.unwrap().as_str() {
+        // The traces are written as messages instead
+        _ if MessageFormat::from_options(&opts)? == MessageFormat::Json => Format::Messages,
+        "json" => Format::Json,
+        "text" => Format::Text,
+        format => return Err(CliError::InvalidArgument("format", format.to_owned())),
+    };
+
+    if selection.is_empty() && !top_only {
+        return Err(CliError::MissingArgument(
+            "`line`, `def` or `class` is required unless `top` is used".to_owned(),
+        ));
+    }
 
     let target_file = explicit_target_file.or(match &transpile_target {
         TranspileUnit::File(p) => Some(p.clone()),
-        TranspileUnit::Module(_) => None,
+        TranspileUnit::Module(_) | TranspileUnit::Source(_) => None,
     });
 
     // Assert that --line is not used without a target
-    if line.is_some() && target_file.is_none() {
+    let is_module = matches!(transpile_target, TranspileUnit::Module(_));
+    if !selection.ranges.is_empty() && is_module && target_file.is_none() {
         return Err(CliError::RedundantParameter(
             "`line` cannot be used without a specific target file".to_owned(),
         ));
@@ -78,8 +140,9 @@ fn resolve_args(matches: &clap::ArgMatches) -> Result<Config> {
     Ok(Config {
         transpile_target,
         target_file,
-        line,
+        selection,
         top_only,
+        format,
     })
 }
 
@@ -92,65 +155,398 @@ struct Config {
     /// The target file relative to module root if separate from the transpiled
     /// module
     target_file: Option<PathBuf>,
-    line: Option<usize>,
+    selection: Selection,
     top_only: bool,
+    format: Format,
+}
+
+/// The functions and classes of a selection that were not found, as the
+/// keyword and the name, eg. ("def", "price").
+type Missing<'s> = Vec<(&'static str, &'s str)>;
+
+/// The lines selected with `--line`, `--def` and `--class`.
+struct Selection {
+    ranges: Vec<RangeInclusive<usize>>,
+    defs: Vec<String>,
+    classes: Vec<String>,
+}
+
+impl Selection {
+    fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.defs.is_empty() && self.classes.is_empty()
+    }
+
+    /// Returns the selected lines of `source` in order, and the functions and
+    /// classes that were not found in it. Empty and comment lines are skipped,
+    /// unless selected as a single line. Ranges are clipped to the end of
+    /// `source`, and ranges that start past it are invalid.
+    fn lines<'s>(&'s self, source: &str) -> Result<(Vec<usize>, Missing<'s>)> {
+        let source_lines = source.lines().collect::<Vec<_>>();
+        let mut ranges = self.ranges.clone();
+        let mut missing = vec![];
+        let symbols = self
+            .defs
+            .iter()
+            .map(|name| ("def", name))
+            .chain(self.classes.iter().map(|name| ("class", name)));
+        for (keyword, name) in symbols {
+            match symbol_lines(&source_lines, keyword, name) {
+                Some(range) => ranges.push(range),
+                None => missing.push((keyword, name.as_str())),
+            }
+        }
+
+        let mut lines = vec![];
+        for range in ranges {
+            if *range.start() > source_lines.len() {
+                return Err(CliError::InvalidArgument(
+                    "line",
+                    format!(
+                        "{}, the file has {} line(s)",
+                        range.start(),
+                        source_lines.len()
+                    ),
+                ));
+            }
+            let single = range.start() == range.end();
+            for line in *range.start()..=(*range.end()).min(source_lines.len()) {
+                let code = source_lines[line - 1].trim();
+                if single || !(code.is_empty() || code.starts_with('#')) {
+                    lines.push(line);
+                }
+            }
+        }
+        lines.sort_unstable();
+        lines.dedup();
+        Ok((lines, missing))
+    }
+}
+
+/// Parses a line, eg. "10", an inclusive range of lines, eg. "10..25", or the
+/// lines from one to the end of the file, eg. "10..".
+fn parse_line_range(value: &str) -> Result<RangeInclusive<usize>> {
+    let invalid = || CliError::InvalidArgument("line", value.to_owned());
+    let parse = |line: &str| match line.trim().parse::<usize>() {
+        Ok(line) if line > 0 => Ok(line),
+        _ => Err(invalid()),
+    };
+
+    let mut bounds = value.splitn(2, "..");
+    // Unwrap is safe because split always returns at least one item
+    let start = parse(bounds.next().unwrap())?;
+    let end = match bounds.next() {
+        Some(end) if end.trim().is_empty() => usize::MAX,
+        Some(end) => parse(end)?,
+        None => start,
+    };
+    if end < start {
+        return Err(invalid());
+    }
+    Ok(start..=end)
+}
+
+/// Returns the lines of the function or class `name`, including its
+/// decorators. `keyword` is either "def" or "class".
+fn symbol_lines(lines: &[&str], keyword: &str, name: &str) -> Option<RangeInclusive<usize>> {
+    let indent_of = |line: &str| line.len() - line.trim_start().len();
+
+    let def_idx = lines.iter().position(|line| {
+        let line = line.trim_start();
+        let line = line.strip_prefix("async ").unwrap_or(line);
+        line.strip_prefix(keyword)
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .and_then(|rest| rest.trim_start().strip_prefix(name))
+            .map_or(false, |rest| {
+                rest.trim_start().starts_with(|c| c == '(' || c == ':')
+            })
+    })?;
+    let indent = indent_of(lines[def_idx]);
+
+    let mut start = def_idx;
+    while start > 0 && lines[start - 1].trim_start().starts_with('@') {
+        start -= 1;
+    }
+
+    // The body consists of the following lines that are indented deeper
+    let mut end = def_idx;
+    for (idx, line) in lines.iter().enumerate().skip(def_idx + 1) {
+        let code = line.trim();
+        if code.is_empty() || code.starts_with('#') {
+            continue;
+        }
+        if indent_of(line) <= indent {
+            break;
+        }
+        end = idx;
+    }
+
+    Some(start + 1..=end + 1)
+}
+
+/// The output format of traces.
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    /// Human readable text through the logger
+    Text,
+    /// One JSON object per trace in stdout
+    Json,
+    /// Line-traced messages among the other JSON messages in stdout
+    Messages,
+}
+
+/// The four steps of transpiling a file or a line.
+#[derive(Serialize)]
+pub struct Steps<'t> {
+    python_source: &'t str,
+    python_ast: &'t str,
+    rust_ast: &'t str,
+    rust_source: &'t str,
+}
+
+impl<'t> Steps<'t> {
+    /// Reads the steps from a trace of serpent.
+    pub fn new(trace: &'t [String]) -> Steps<'t> {
+        Steps {
+            python_source: &trace[0],
+            python_ast: &trace[1],
+            rust_ast: &trace[2],
+            rust_source: &trace[3],
+        }
+    }
+
+    /// Shows the steps as text through the logger.
+    pub fn log(&self) {
+        info!("{}:\n{}\n", "Python source", self.python_source);
+        info!("{}:\n{}\n", "Python AST", self.python_ast);
+        info!("{}:\n{}\n", "Rust AST", self.rust_ast);
+        info!("{}:\n{}\n", "Rust source", self.rust_source);
+    }
+}
+
+/// The steps of transpiling a file or a line, with the file and line they were
+/// traced from.
+#[derive(Serialize)]
+struct Trace<'t> {
+    path: &'t Path,
+    line: Option<usize>,
+    #[serde(flatten)]
+    steps: Steps<'t>,
 }
 
 fn do_work(cfg: &Config) -> Result<()> {
-    match &cfg.transpile_target {
+    if cfg.format != Format::Messages {
+        return trace(cfg).map(drop);
+    }
+
+    // Failures and a summary end the messages, also for a failed run
+    let result = trace(cfg);
+    let failed = match &result {
+        Ok(_) => 0,
+        Err(e) => message::emit_failures(e)?,
+    };
+    message::emit(&Message::Summary {
+        success: result.is_ok(),
+        files: *result.as_ref().unwrap_or(&0),
+        failed,
+    })?;
+    result.map(drop)
+}
+
+/// Traces the selected lines of the input. Returns the number of files traced.
+fn trace(cfg: &Config) -> Result<usize> {
+    let traced = match &cfg.transpile_target {
         TranspileUnit::File(path) => {
-            let transpiled = serpent::transpile_file(&path)?;
-
-            let trace = if cfg.top_only {
-                // "Top only" can show output for all lines
-                transpiled.trace_top(cfg.line)
-            } else {
-                // .unwrap() is safe, because line is required when `top_only` is false
-                let line = cfg.line.unwrap();
-                transpiled.trace_steps_for_line(line, false)
-            }?;
-
-            print_trace(&trace);
+            let transpiled = serpent::transpile_file(&path)
+                .map_err(|e| diagnostic::file_error(path, None, e))?;
+            trace_single_file(&transpiled, path, cfg)?;
+            1
+        }
+        TranspileUnit::Source(source) => {
+            let source_file = SourceFile::new(source)?;
+            let stdin_path = Path::new("<stdin>");
+            let transpiled = serpent::transpile_file(source_file.path())
+                .map_err(|e| diagnostic::file_error(source_file.path(), Some(stdin_path), e))?;
+            trace_single_file(&transpiled, stdin_path, cfg)?;
+            1
         }
         TranspileUnit::Module(path) => {
-            let transpiled = serpent::transpile_module(&path)?;
-
-            let line = cfg.line;
+            let transpiled = serpent::transpile_module(&path).map_err(|e| {
+                tp::module_error(path, e, |path| {
+                    TranspileModuleBuilder::new(path).config(TranspileConfig::default())
+                })
+            })?;
 
             match &cfg.target_file {
                 Some(p) => {
-                    if cfg.top_only {
-                        let trace = transpiled.file_by_file_path(&p).unwrap().trace_top(line)?;
-                        print_trace(&trace);
-                    } else {
-                        // .unwrap() is safe, because line is required when `top_only` is false
-                        let line = cfg.line.unwrap();
-                        let trace = transpiled
-                            .file_by_file_path(&p)
-                            .unwrap()
-                            .trace_steps_for_line(line, false)?;
-                        print_trace(&trace);
+                    let file = transpiled
+                        .file_by_file_path(&p)
+                        .ok_or_else(|| CliError::NotInModule(p.clone()))?;
+                    trace_single_file(file, p, cfg)?;
+                    1
+                }
+                None if !cfg.selection.is_empty() => {
+                    // Look for the functions and classes in all files
+                    let mut missing_everywhere: Option<Vec<_>> = None;
+                    for tp_file in transpiled.files() {
+                        let missing = trace_file(tp_file, tp_file.source_path(), cfg)?;
+                        missing_everywhere = Some(match missing_everywhere {
+                            Some(prev) => {
+                                prev.into_iter().filter(|s| missing.contains(s)).collect()
+                            }
+                            None => missing,
+                        });
+                    }
+                    if let Some((keyword, name)) = missing_everywhere.unwrap_or_default().first() {
+                        return Err(CliError::SymbolNotFound(keyword, name.to_string()));
                     }
+                    transpiled.files().len()
                 }
                 None => {
                     // Trace all files
                     for tp_file in transpiled.files() {
                         let trace = tp_file.trace_top(None)?;
 
-                        info!("Path: {:?}\n", tp_file.source_path());
-                        print_trace(&trace);
+                        start_file(tp_file.source_path(), cfg.format)?;
+                        print_trace(&trace, tp_file.source_path(), None, cfg.format)?;
                     }
+                    transpiled.files().len()
                 }
             }
         }
     };
 
+    Ok(traced)
+}
+
+/// Traces the selected lines of `file`, showing it as `display_path` in the
+/// output. Returns the selected functions and classes that were not found in
+/// the file.
+fn trace_file<'c>(
+    file: &TranspiledFile,
+    display_path: &Path,
+    cfg: &'c Config,
+) -> Result<Vec<(&'static str, &'c str)>> {
+    if cfg.selection.is_empty() {
+        // "Top only" can show output for all lines
+        let trace = file.trace_top(None)?;
+        if cfg.format == Format::Messages {
+            start_file(display_path, cfg.format)?;
+        }
+        print_trace(&trace, display_path, None, cfg.format)?;
+        return Ok(vec![]);
+    }
+
+    let source = fs::read_to_string(file.source_path())?;
+    let (lines, missing) = cfg.selection.lines(&source)?;
+
+    if !lines.is_empty() {
+        start_file(display_path, cfg.format)?;
+    }
+    for line in lines {
+        let trace = if cfg.top_only {
+            file.trace_top(Some(line))
+        } else {
+            file.trace_steps_for_line(line, false)
+        }?;
+        if cfg.format == Format::Text {
+            info!("Line {}:\n", line);
+        }
+        print_trace(&trace, display_path, Some(line), cfg.format)?;
+    }
+    Ok(missing)
+}
+
+/// Traces the selected lines of a single file. Every selected function and
+/// class must be found in it.
+fn trace_single_file(file: &TranspiledFile, display_path: &Path, cfg: &Config) -> Result<()> {
+    match trace_file(file, display_path, cfg)?.first() {
+        Some((keyword, name)) => Err(CliError::SymbolNotFound(keyword, name.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Shows the path of a file before its traces.
+fn start_file(path: &Path, format: Format) -> Result<()> {
+    match format {
+        Format::Text => info!("Path: {:?}\n", path),
+        Format::Json => {}
+        Format::Messages => message::emit(&Message::FileStarted { path })?,
+    }
+    Ok(())
+}
+
+fn print_trace(trace: &[String], path: &Path, line: Option<usize>, format: Format) -> Result<()> {
+    let steps = Steps::new(trace);
+    match format {
+        Format::Text => steps.log(),
+        Format::Json => {
+            let trace = Trace { path, line, steps };
+            println!("{}", serde_json::to_string(&trace)?);
+        }
+        Format::Messages => message::emit(&Message::LineTraced { path, line, steps })?,
+    }
     Ok(())
 }
 
-fn print_trace(trace: &[String]) {
-    info!("{}:\n{}\n", "Python source", trace[0]);
-    info!("{}:\n{}\n", "Python AST", trace[1]);
-    info!("{}:\n{}\n", "Rust AST", trace[2]);
-    info!("{}:\n{}\n", "Rust source", trace[3]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selection(ranges: Vec<RangeInclusive<usize>>, defs: &[&str]) -> Selection {
+        Selection {
+            ranges,
+            defs: defs.iter().map(|name| name.to_string()).collect(),
+            classes: vec![],
+        }
+    }
+
+    #[test]
+    fn parse_line_range_reads_lines_and_ranges() {
+        assert_eq!(parse_line_range("5").unwrap(), 5..=5);
+        assert_eq!(parse_line_range("3..7").unwrap(), 3..=7);
+        assert_eq!(parse_line_range(" 3 .. 7 ").unwrap(), 3..=7);
+        assert_eq!(parse_line_range("4..4").unwrap(), 4..=4);
+        assert_eq!(parse_line_range("40..").unwrap(), 40..=usize::MAX);
+    }
+
+    #[test]
+    fn parse_line_range_rejects_reversed_and_empty_ranges() {
+        for value in &["7..3", "", "..", "..5", "0", "0..3", "a..b", "3..x"] {
+            assert!(
+                parse_line_range(value).is_err(),
+                "{:?} should be rejected",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn lines_clips_ranges_to_the_end_of_the_file() {
+        let source = "a = 1\n\n# comment\nb = 2\n";
+        let selection = selection(vec![1..=usize::MAX], &[]);
+
+        let (lines, missing) = selection.lines(source).unwrap();
+
+        assert_eq!(lines, vec![1, 4]);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn lines_rejects_ranges_that_start_past_the_end() {
+        let source = "a = 1\nb = 2\n";
+
+        assert!(selection(vec![3..=5], &[]).lines(source).is_err());
+        assert!(selection(vec![2..=5], &[]).lines(source).is_ok());
+    }
+
+    #[test]
+    fn lines_selects_functions_with_their_decorators() {
+        let source = "@cached\ndef f(x):\n    return x\n\ndef g():\n    pass\n";
+        let selection = selection(vec![], &["f", "h"]);
+
+        let (lines, missing) = selection.lines(source).unwrap();
+
+        assert_eq!(lines, vec![1, 2, 3]);
+        assert_eq!(missing, vec![("def", "h")]);
+    }
 }
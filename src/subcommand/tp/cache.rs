@@ -0,0 +1,158 @@
+//! Content-hash cache for skipping unchanged files in module transpilation.
+//!
+//! Each Python file is keyed on a hash of its source, the remap file and
+//! other shared inputs, the transpilation configuration, the options that
+//! affect the output and the serpent version. When the keys of all files
+//! match the ones recorded during the previous run and their outputs, including
+//! the manifest, are still as written, the module is not transpiled at all.
+//! Otherwise the whole module is transpiled again, since the output of
+//! a file depends on the other files through the import map, and only the
+//! outputs that changed are rewritten.
+use super::Result;
+use fs_err as fs;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serpent::TranspileConfig;
+use sha2::{Digest, Sha256};
+
+use std::{collections::BTreeMap, path};
+
+/// Name of the default cache directory, placed in the output directory.
+pub const DEFAULT_CACHE_DIR: &str = ".serpent-cache";
+
+const INDEX_FILE: &str = "index.toml";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    /// Hash of the manifest as written, if one was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    manifest: Option<String>,
+    files: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Hash of everything that affects the output
+    key: String,
+    /// Output path relative to the output directory
+    output: String,
+}
+
+pub struct Cache {
+    dir: path::PathBuf,
+    index: Index,
+    /// Hasher primed with the inputs shared by every file
+    common: Sha256,
+}
+
+impl Cache {
+    /// Opens the cache in `dir`, starting from an empty cache if none exists
+    /// or if it cannot be read. `shared_inputs` are the files that affect the
+    /// output of every Python file, eg. the remap file, and `options` the
+    /// command line options that do.
+    pub fn open(
+        dir: impl AsRef<path::Path>,
+        shared_inputs: &[&path::Path],
+        t_cfg: &TranspileConfig,
+        options: &str,
+    ) -> Result<Cache> {
+        let dir = dir.as_ref().to_path_buf();
+
+        let index_path = dir.join(INDEX_FILE);
+        let index = if index_path.exists() {
+            match toml::from_str(&fs::read_to_string(&index_path)?) {
+                Ok(index) => index,
+                Err(e) => {
+                    warn!("Ignoring unreadable cache index {:?}: {}", index_path, e);
+                    Index::default()
+                }
+            }
+        } else {
+            Index::default()
+        };
+
+        let mut common = Sha256::new();
+        common.update(crate::SERPENT_VERSION);
+        common.update(crate::PKG_VERSION);
+        common.update(format!("{:?}", t_cfg));
+        common.update(options);
+        for input in shared_inputs {
+            common.update(fs::read(input)?);
+        }
+
+        Ok(Cache { dir, index, common })
+    }
+
+    /// Computes the cache key for a Python file.
+    pub fn key(&self, python_path: impl AsRef<path::Path>) -> Result<String> {
+        let mut hasher = self.common.clone();
+        hasher.update(fs::read(python_path.as_ref())?);
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Returns true if `name` was transpiled with the same `key` before and its
+    /// output still exists in `out_dir`.
+    pub fn is_fresh(&self, name: &str, key: &str, out_dir: impl AsRef<path::Path>) -> bool {
+        match self.index.files.get(name) {
+            Some(entry) => entry.key == key && out_dir.as_ref().join(&entry.output).is_file(),
+            None => false,
+        }
+    }
+
+    /// Returns true if the manifest at `manifest_path` is still as it was
+    /// recorded.
+    pub fn is_manifest_fresh(&self, manifest_path: &path::Path) -> Result<bool> {
+        match &self.index.manifest {
+            Some(hash) if manifest_path.is_file() => Ok(*hash == hash_file(manifest_path)?),
+            _ => Ok(false),
+        }
+    }
+
+    /// Records the manifest at `manifest_path` as written.
+    pub fn update_manifest(&mut self, manifest_path: &path::Path) -> Result<()> {
+        self.index.manifest = Some(hash_file(manifest_path)?);
+        Ok(())
+    }
+
+    /// Returns the number of files recorded in the cache.
+    pub fn file_count(&self) -> usize {
+        self.index.files.len()
+    }
+
+    /// Forgets every recorded file and the manifest, before recording a new
+    /// transpilation of the whole module.
+    pub fn clear(&mut self) {
+        self.index = Index::default();
+    }
+
+    /// Records that `name` was transpiled with `key` into `output`.
+    pub fn update(&mut self, name: String, key: String, output: String) {
+        self.index.files.insert(name, Entry { key, output });
+    }
+
+    /// Writes the cache index into the cache directory.
+    pub fn save(&self) -> Result<()> {
+        if !self.dir.exists() {
+            fs::create_dir_all(&self.dir)?;
+        }
+        let index_path = self.dir.join(INDEX_FILE);
+        debug!("Writing cache index into {:?}", &index_path);
+        super::write_file(index_path, &toml::to_string(&self.index)?)
+    }
+}
+
+fn hash_file(path: &path::Path) -> Result<String> {
+    Ok(format!("{:x}", Sha256::digest(&fs::read(path)?)))
+}
+
+/// Returns `path` relative to `root` with forward slashes, as used for the
+/// names and outputs recorded in the cache.
+pub fn relative_name(path: &path::Path, root: &path::Path) -> String {
+    // Unwrap is safe because inputs and outputs are always within their roots
+    path.strip_prefix(root)
+        .unwrap()
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
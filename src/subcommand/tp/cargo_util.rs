@@ -2,116 +2,370 @@ use crate::error::CliError;
 
 use super::Result;
 use fs_err as fs;
-use log::info;
+use log::{debug, warn};
 use toml::{map::Map as TomlMap, Value as TomlValue};
 
-use std::path;
+use std::{fmt, path, str};
 
+/// The Rust edition of the emitted crate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edition {
+    E2018,
+    E2021,
+    E2024,
+}
+
+impl Edition {
+    pub const VALUES: &'static [&'static str] = &["2018", "2021", "2024"];
+}
+
+impl Default for Edition {
+    fn default() -> Self {
+        Edition::E2018
+    }
+}
+
+impl str::FromStr for Edition {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "2018" => Ok(Edition::E2018),
+            "2021" => Ok(Edition::E2021),
+            "2024" => Ok(Edition::E2024),
+            _ => Err(CliError::TomlContentError(
+                TomlValue::String(s.to_owned()),
+                "one of \"2018\", \"2021\" or \"2024\"",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Edition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Edition::E2018 => write!(f, "2018"),
+            Edition::E2021 => write!(f, "2021"),
+            Edition::E2024 => write!(f, "2024"),
+        }
+    }
+}
+
+/// A library or binary target of a manifest.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub name: String,
+    /// Path of the target relative to the manifest, eg. "src/main.rs"
+    pub path: String,
+}
+
+impl Target {
+    /// Creates a target named after the file stem of `path`.
+    pub fn from_path(path: impl AsRef<path::Path>) -> Target {
+        let path = path.as_ref();
+        Target {
+            // Unwrap is safe because target paths always point to ".rs" files
+            name: path.file_stem().unwrap().to_string_lossy().into_owned(),
+            path: path.to_string_lossy().into_owned(),
+        }
+    }
+
+    fn to_toml(&self) -> TomlValue {
+        let mut table = TomlMap::new();
+        table.insert("name".to_owned(), TomlValue::String(self.name.clone()));
+        table.insert("path".to_owned(), TomlValue::String(self.path.clone()));
+        TomlValue::Table(table)
+    }
+}
+
+/// A Cargo manifest of a transpiled crate.
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    /// The `[package]` section. Always contains the name, version and edition.
+    pub package: TomlMap<String, TomlValue>,
+    pub lib: Option<Target>,
+    pub bins: Vec<Target>,
+    /// The `[dependencies]` section. Each dependency is either a version
+    /// string or a table in the Cargo dependency syntax, eg. `{ version =
+    /// "0.15", features = ["rayon"] }`.
+    pub dependencies: TomlMap<String, TomlValue>,
+    /// Any other sections, eg. `[features]`, `[dev-dependencies]` or
+    /// `[profile.release]`. These are emitted as is.
+    pub extra: TomlMap<String, TomlValue>,
+}
+
+impl Manifest {
+    pub fn new(name: &str, edition: Edition) -> Manifest {
+        let mut package = TomlMap::new();
+        package.insert("name".to_owned(), TomlValue::String(name.to_owned()));
+        package.insert("version".to_owned(), TomlValue::String("0.1.0".to_owned()));
+        package.insert("edition".to_owned(), TomlValue::String(edition.to_string()));
+
+        Manifest {
+            package,
+            ..Default::default()
+        }
+    }
+
+    /// Merges user-provided manifest contents into this manifest.
+    ///
+    /// The `package` table of `user` is merged into `[package]` and all other
+    /// tables are emitted as extra sections. The `edition`, if any, is
+    /// replaced by `edition_override`.
+    pub fn merge_user(
+        &mut self,
+        user: &TomlMap<String, TomlValue>,
+        edition_override: Option<Edition>,
+    ) -> Result<()> {
+        for (key, value) in user {
+            match (key.as_str(), value) {
+                ("package", TomlValue::Table(package)) => {
+                    for (key, value) in package {
+                        self.package.insert(key.clone(), value.clone());
+                    }
+                }
+                ("package", value) => {
+                    return Err(CliError::TomlContentError(value.clone(), "table"));
+                }
+                (_, value) => {
+                    self.extra.insert(key.clone(), value.clone());
+                }
+            }
+        }
+
+        // Verify the edition
+        match (edition_override, self.package.get("edition")) {
+            (Some(edition), _) => {
+                self.package
+                    .insert("edition".to_owned(), TomlValue::String(edition.to_string()));
+            }
+            (None, Some(TomlValue::String(edition))) => {
+                edition.parse::<Edition>()?;
+            }
+            (None, Some(value)) => {
+                return Err(CliError::TomlContentError(value.clone(), "String"));
+            }
+            (None, None) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Renders the manifest as TOML.
+    pub fn to_toml(&self) -> Result<String> {
+        for value in self.dependencies.values() {
+            match value {
+                TomlValue::String(_) | TomlValue::Table(_) => {}
+                val => return Err(CliError::TomlContentError(val.clone(), "String or table")),
+            }
+        }
+
+        let mut sections = vec![("package".to_owned(), TomlValue::Table(self.package.clone()))];
+        if let Some(lib) = &self.lib {
+            sections.push(("lib".to_owned(), lib.to_toml()));
+        }
+        if !self.bins.is_empty() {
+            let bins = self.bins.iter().map(Target::to_toml).collect();
+            sections.push(("bin".to_owned(), TomlValue::Array(bins)));
+        }
+        if !self.dependencies.is_empty() {
+            sections.push((
+                "dependencies".to_owned(),
+                TomlValue::Table(self.dependencies.clone()),
+            ));
+        }
+        // Generated sections take precedence over extra sections
+        for (key, value) in &self.extra {
+            if sections.iter().any(|(k, _)| k == key) {
+                warn!("Ignoring [{}] in manifest contents of remap file", key);
+            } else {
+                sections.push((key.clone(), value.clone()));
+            }
+        }
+
+        // Render the sections one by one to keep them in the above order
+        let mut content = String::new();
+        for (key, value) in &sections {
+            let mut section = TomlMap::new();
+            section.insert(key.clone(), value.clone());
+            if !content.is_empty() {
+                content.push('\n');
+            }
+            content.push_str(&toml::to_string(&TomlValue::Table(section))?);
+        }
+        Ok(content)
+    }
+}
+
+/// Writes the manifest into the crate at `path`. Returns the path of the
+/// manifest, or none if an existing manifest was kept or already had the same
+/// contents. An unchanged manifest is not rewritten, so that cargo does not
+/// rebuild the crate because of it.
 pub fn create_manifest(
     path: impl AsRef<path::Path>,
     overwrite_previous: bool,
-    deps: Option<&TomlMap<String, TomlValue>>,
-    bin_target: Option<impl AsRef<path::Path>>,
-    lib_target: Option<impl AsRef<path::Path>>,
-) -> Result<()> {
+    manifest: &Manifest,
+) -> Result<Option<path::PathBuf>> {
     let path = path.as_ref();
     let manifest_path = path.join("Cargo.toml");
     if manifest_path.exists() {
         if overwrite_previous {
-            info!("{:?} already exists, deleting previous", &manifest_path);
-            fs::remove_file(&manifest_path)?;
+            debug!("{:?} already exists, overwriting previous", &manifest_path);
         } else {
-            info!(
+            debug!(
                 "{:?} already exists, skipping because overwrite_manifest = false",
                 &manifest_path
             );
+            return Ok(None);
         }
     }
-    info!("Writing manifest into {:?}", &manifest_path);
-    emit_manifest(&manifest_path, deps, bin_target, lib_target)
+    if emit_manifest(&manifest_path, manifest)? {
+        Ok(Some(manifest_path))
+    } else {
+        debug!("{:?} is unchanged", &manifest_path);
+        Ok(None)
+    }
 }
 
-pub fn emit_manifest(
-    manifest_filepath: &path::Path,
-    deps: Option<&TomlMap<String, TomlValue>>,
-    bin_target: Option<impl AsRef<path::Path>>,
-    lib_target: Option<impl AsRef<path::Path>>,
-) -> Result<()> {
-    use cargo_toml_builder::prelude::*;
-
-    let mut cargo_toml = CargoToml::builder();
-    cargo_toml.author("automatically transpiled with serpent");
-
-    // Generate a name
-    let name = format!(
-        "{}",
-        manifest_filepath
-            .parent()
-            .unwrap()
-            .file_stem()
-            .unwrap()
-            .to_str()
-            .unwrap(),
-    );
-    cargo_toml.name(&name);
-
-    if let Some(deps) = deps {
-        let deps = toml_into_deps(deps)?;
-        cargo_toml.dependencies(&deps);
-    }
+/// Writes the manifest into `manifest_filepath` unless it already has the same
+/// contents. Returns true if the manifest was written.
+pub fn emit_manifest(manifest_filepath: &path::Path, manifest: &Manifest) -> Result<bool> {
+    use super::write_file_if_changed;
+    write_file_if_changed(manifest_filepath, &manifest.to_toml()?)
+}
+
+/// Generates a crate name from the directory of a manifest.
+pub fn crate_name(crate_path: &path::Path) -> String {
+    crate_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "transpiled".to_owned())
+}
 
-    // Add bin target
-    if let Some(target_path) = bin_target {
-        let target_path = target_path.as_ref();
+/// Returns the name by which binaries refer to the library of the crate at
+/// `crate_path`, with `-` replaced by `_`. The name comes from the existing
+/// manifest if it is kept, or else from the `user` tables of the remap file or
+/// the directory of the crate.
+pub fn lib_name(
+    crate_path: &path::Path,
+    keep_existing: bool,
+    user: Option<&TomlMap<String, TomlValue>>,
+) -> Result<String> {
+    let manifest_path = crate_path.join("Cargo.toml");
+    let existing = if keep_existing && manifest_path.exists() {
+        Some(toml::from_str::<TomlMap<String, TomlValue>>(
+            &fs::read_to_string(&manifest_path)?,
+        )?)
+    } else {
+        None
+    };
+    let name = existing.as_ref().or(user).and_then(|tables| {
+        ["lib", "package"]
+            .iter()
+            .find_map(|table| tables.get(*table)?.get("name"))
+    });
+    let name = match name {
+        Some(TomlValue::String(name)) => name.clone(),
+        Some(value) => return Err(CliError::TomlContentError(value.clone(), "String")),
+        None => crate_name(crate_path),
+    };
+    Ok(name.replace('-', "_"))
+}
 
-        // Extract stem as target name
-        let name = target_path.file_stem().unwrap().to_str().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let target = BinTarget::new()
-            .name(name)
-            .path(target_path.to_str().unwrap())
-            .build();
-        cargo_toml.bin(target);
+    fn manifest_with_user_contents(user: &str, edition_override: Option<Edition>) -> Manifest {
+        let mut manifest = Manifest::new("transpiled", Edition::E2018);
+        manifest.lib = Some(Target::from_path("src/lib.rs"));
+        manifest.bins = vec![Target::from_path("src/bin/run.rs")];
+        manifest
+            .dependencies
+            .insert("log".to_owned(), TomlValue::String("0.4".to_owned()));
+        let user = user.parse::<TomlValue>().unwrap();
+        manifest
+            .merge_user(user.as_table().unwrap(), edition_override)
+            .unwrap();
+        manifest
     }
 
-    // Add lib target
-    if let Some(target_path) = lib_target {
-        let target_path = target_path.as_ref();
+    #[test]
+    fn to_toml_parses_back_into_the_manifest() {
+        let manifest = manifest_with_user_contents(
+            r#"
+            [package]
+            edition = "2021"
+            authors = ["Serpent"]
+
+            [features]
+            default = ["fast"]
+            fast = []
+
+            [profile.release]
+            lto = true
+            opt-level = 3
+            "#,
+            None,
+        );
+
+        let emitted = manifest.to_toml().unwrap().parse::<TomlValue>().unwrap();
 
-        // Extract stem as target name
-        let name = target_path.file_stem().unwrap().to_str().unwrap();
+        let expected = r#"
+            [package]
+            name = "transpiled"
+            version = "0.1.0"
+            edition = "2021"
+            authors = ["Serpent"]
 
-        let target = LibTarget::new()
-            .name(name)
-            .path(target_path.to_str().unwrap())
-            .build();
-        cargo_toml.lib(target);
+            [lib]
+            name = "lib"
+            path = "src/lib.rs"
+
+            [[bin]]
+            name = "run"
+            path = "src/bin/run.rs"
+
+            [dependencies]
+            log = "0.4"
+
+            [features]
+            default = ["fast"]
+            fast = []
+
+            [profile.release]
+            lto = true
+            opt-level = 3
+            "#
+        .parse::<TomlValue>()
+        .unwrap();
+        assert_eq!(emitted, expected);
     }
 
-    let content = format!("{}", cargo_toml.build()?);
+    #[test]
+    fn to_toml_emits_the_edition_override() {
+        let manifest =
+            manifest_with_user_contents("[package]\nedition = \"2021\"\n", Some(Edition::E2024));
 
-    // Insert `edition = "2018"`
-    let mut ncontent = vec![];
-    let mut lines = content.lines();
-    while let Some(line) = lines.next() {
-        ncontent.push(line);
-        ncontent.push("\n");
-        if line.contains("[package]") {
-            ncontent.push("edition = \"2018\"\n");
-        }
+        let emitted = manifest.to_toml().unwrap().parse::<TomlValue>().unwrap();
+
+        assert_eq!(
+            emitted["package"]["edition"],
+            TomlValue::String("2024".to_owned())
+        );
     }
-    let content = ncontent.concat();
 
-    use super::write_file;
-    write_file(manifest_filepath, &content)
-}
+    #[test]
+    fn lib_name_prefers_the_name_in_the_user_tables() {
+        let user = "[package]\nname = \"my-tool\"\n"
+            .parse::<TomlValue>()
+            .unwrap();
+        let crate_path = path::Path::new("does/not/exist/out-dir");
 
-use cargo_toml_builder::types::Dependency;
-fn toml_into_deps(toml: &TomlMap<String, TomlValue>) -> Result<Vec<Dependency>> {
-    toml.iter()
-        .map(|(key, value)| match value {
-            TomlValue::String(version) => Ok(Dependency::version(key, version)),
-            val => return Err(CliError::TomlContentError(val.clone(), "String")),
-        })
-        .collect::<Result<Vec<Dependency>>>()
+        assert_eq!(
+            lib_name(crate_path, true, user.as_table()).unwrap(),
+            "my_tool"
+        );
+        assert_eq!(lib_name(crate_path, true, None).unwrap(), "out_dir");
+    }
 }
@@ -0,0 +1,107 @@
+//! Runs `cargo check` on a transpiled crate and maps the reported diagnostics
+//! back to the Python lines that produced them.
+use super::Result;
+use crate::{error::CliError, line_map::LineMap};
+use log::{debug, error, info, warn};
+use serde_json::Value as JsonValue;
+
+use std::{path, process};
+
+/// Links a Rust file in the transpiled crate to the Python file it came from.
+pub struct Origin {
+    /// Path of the Rust file relative to the crate root, eg. "src/lib.rs"
+    pub rust_path: path::PathBuf,
+    pub python_path: path::PathBuf,
+    pub line_map: LineMap,
+}
+
+/// Runs `cargo check` in `crate_path` and reports every diagnostic along with
+/// its Python origin, if known.
+pub fn check_crate(crate_path: impl AsRef<path::Path>, origins: &[Origin]) -> Result<()> {
+    let crate_path = crate_path.as_ref();
+
+    let manifest_path = crate_path.join("Cargo.toml");
+    if !manifest_path.exists() {
+        return Err(CliError::ManifestNotFound(manifest_path));
+    }
+
+    info!("Running `cargo check` in {:?}", crate_path);
+    let output = process::Command::new("cargo")
+        .args(&["check", "--message-format=json", "--offline"])
+        .current_dir(crate_path)
+        .output()?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut error_count = 0;
+    for line in stdout.lines() {
+        let msg = match serde_json::from_str::<JsonValue>(line) {
+            Ok(msg) => msg,
+            Err(e) => {
+                debug!("Skipping non-JSON line from cargo ({}): {}", e, line);
+                continue;
+            }
+        };
+        if msg["reason"] != "compiler-message" {
+            continue;
+        }
+
+        let message = &msg["message"];
+        let level = message["level"].as_str().unwrap_or("error");
+        let rendered = message["rendered"]
+            .as_str()
+            .map(str::trim_end)
+            .unwrap_or_else(|| message["message"].as_str().unwrap_or_default());
+
+        let python_note = primary_span(message)
+            .and_then(|(file, line)| python_origin(origins, &file, line))
+            .map(|(py_path, py_line)| match py_line {
+                Some(py_line) => format!("\n  = python: {}:{}", py_path.display(), py_line),
+                None => format!("\n  = python: {}", py_path.display()),
+            })
+            .unwrap_or_default();
+
+        match level {
+            "error" | "error: internal compiler error" => {
+                error_count += 1;
+                error!("{}{}", rendered, python_note);
+            }
+            "warning" => warn!("{}{}", rendered, python_note),
+            _ => info!("{}{}", rendered, python_note),
+        }
+    }
+
+    if !output.status.success() || error_count != 0 {
+        return Err(CliError::CargoCheckFailed(error_count));
+    }
+
+    info!("`cargo check` passed for {:?}", crate_path);
+    Ok(())
+}
+
+/// Returns the file name and starting line of the primary span of a rustc
+/// diagnostic.
+fn primary_span(message: &JsonValue) -> Option<(path::PathBuf, usize)> {
+    let spans = message["spans"].as_array()?;
+    let span = spans
+        .iter()
+        .find(|span| span["is_primary"].as_bool().unwrap_or(false))
+        .or_else(|| spans.first())?;
+
+    let file = span["file_name"].as_str()?;
+    let line = span["line_start"].as_u64()? as usize;
+    Some((path::PathBuf::from(file), line))
+}
+
+/// Finds the Python file and line that a Rust file and line were transpiled
+/// from.
+fn python_origin<'o>(
+    origins: &'o [Origin],
+    rust_path: &path::Path,
+    rust_line: usize,
+) -> Option<(&'o path::Path, Option<usize>)> {
+    let origin = origins.iter().find(|o| o.rust_path == rust_path)?;
+    Some((
+        &origin.python_path,
+        origin.line_map.python_line_for(rust_line),
+    ))
+}
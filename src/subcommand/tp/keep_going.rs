@@ -0,0 +1,275 @@
+//! Transpiles as much of a module as possible when some of its files fail.
+//!
+//! serpent transpiles a module in a single pass, so one file that fails aborts
+//! the whole module. The failing files are found by transpiling each file as a
+//! module of its own, and the rest of the module is transpiled from a staged
+//! copy without them. If the rest still fails, the file that makes it fail is
+//! found by staging ever smaller parts of the module, and left out in turn,
+//! until the rest transpiles.
+//!
+//! Without `--keep-going`, the first file that fails on its own is reported
+//! in place of the error of the module.
+use super::Result;
+use crate::diagnostic::Diagnostic;
+use crate::error::CliError;
+use fs_err as fs;
+use log::warn;
+use rayon::prelude::*;
+use serpent::{ApiError, Transpile, TranspileModuleBuilder, TranspiledModule};
+use tempfile::TempDir;
+
+use std::{iter, path};
+
+/// A Python file that could not be transpiled.
+pub struct FailedFile {
+    pub path: path::PathBuf,
+    pub diagnostic: Diagnostic,
+    /// True if the file has a `__main__` block, making it a binary target
+    pub entry_point: bool,
+    /// Rust written in place of the transpiled file
+    pub stub: String,
+}
+
+impl FailedFile {
+    fn new(path: path::PathBuf, diagnostic: Diagnostic) -> Result<FailedFile> {
+        let python_source = fs::read_to_string(&path)?;
+        Ok(FailedFile {
+            stub: stub(&path, &diagnostic, &python_source),
+            entry_point: python_source.contains("__main__"),
+            diagnostic,
+            path,
+        })
+    }
+}
+
+/// A copy of some files of a module, removed when dropped.
+struct StagedModule {
+    /// A directory created for this copy only, so that no other process can
+    /// place files into it
+    _dir: TempDir,
+    path: path::PathBuf,
+    /// `path` with symbolic links resolved, in case serpent resolves them
+    canonical_path: path::PathBuf,
+}
+
+impl StagedModule {
+    fn new(module_input_path: &path::Path, python_files: &[path::PathBuf]) -> Result<StagedModule> {
+        let dir = tempfile::Builder::new()
+            .prefix("serpent-keep-going-")
+            .tempdir()?;
+        // Keep the name of the module, in case serpent derives names from it
+        let name = fs::canonicalize(module_input_path)?
+            .file_name()
+            .map(|name| name.to_owned())
+            .unwrap_or_else(|| "module".into());
+        let path = dir.path().join(name);
+        fs::create_dir(&path)?;
+
+        for python_file in python_files {
+            let staged_file = path.join(relative_path(python_file, module_input_path)?);
+            if let Some(parent) = staged_file.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(python_file, &staged_file)?;
+        }
+        Ok(StagedModule {
+            canonical_path: fs::canonicalize(&path)?,
+            path,
+            _dir: dir,
+        })
+    }
+
+    /// Returns the path in the module that `staged_file` was copied from.
+    fn original_path(
+        &self,
+        staged_file: &path::Path,
+        module_input_path: &path::Path,
+    ) -> Result<path::PathBuf> {
+        let relative = relative_path(staged_file, &self.path)
+            .or_else(|_| relative_path(staged_file, &self.canonical_path))?;
+        Ok(module_input_path.join(relative))
+    }
+}
+
+/// Transpiles the files of a module that can be transpiled, after the module
+/// failed to transpile. Returns the transpiled module along with the files
+/// that failed, or the failures if no file can be transpiled.
+pub fn transpile_partial<F>(
+    module_input_path: &path::Path,
+    module_builder: F,
+) -> Result<(TranspiledModule, Vec<FailedFile>)>
+where
+    F: Fn(&path::Path) -> TranspileModuleBuilder + Sync,
+{
+    // Transpile each file as a module of its own, so that the remap file
+    // applies as it does to the whole module. The files are transpiled in
+    // parallel and collected in order.
+    let python_files = crate::python_files(module_input_path)?;
+    let results = python_files
+        .par_iter()
+        .map(|python_file| transpile_alone(module_input_path, python_file, &module_builder))
+        .collect::<Result<Vec<_>>>()?;
+    let mut failed = vec![];
+    let mut remaining = vec![];
+    for (python_file, result) in python_files.into_iter().zip(results) {
+        match result {
+            Ok(()) => remaining.push(python_file),
+            Err(e) => {
+                let diagnostic = Diagnostic::new(&e, Some(&python_file));
+                failed.push(FailedFile::new(python_file, diagnostic)?);
+            }
+        }
+    }
+
+    // Leave out the files that make the rest of the module fail until it
+    // transpiles
+    while !remaining.is_empty() {
+        let staged = StagedModule::new(module_input_path, &remaining)?;
+        let error = match module_builder(&staged.path).transpile() {
+            Ok(mut transpiled) => {
+                for file in transpiled.files_mut() {
+                    file.source_path =
+                        staged.original_path(&file.source_path, module_input_path)?;
+                }
+                return Ok((transpiled, failed));
+            }
+            Err(e) => e,
+        };
+
+        let (idx, error) = first_failing(module_input_path, &remaining, error, &module_builder)?;
+        let python_file = remaining.remove(idx);
+        warn!(
+            "{:?} transpiles on its own but fails along with other files of the module",
+            python_file
+        );
+        let diagnostic = Diagnostic::new(&error, Some(&python_file)).note(
+            "the file transpiles on its own, the error may be in a file transpiled along with it",
+        );
+        failed.push(FailedFile::new(python_file, diagnostic)?);
+    }
+
+    Err(CliError::Diagnostics(
+        failed.into_iter().map(|failed| failed.diagnostic).collect(),
+    ))
+}
+
+/// Converts an error of transpiling a module into a diagnostic for the first
+/// file that fails as a module of its own, or a diagnostic without a file if
+/// none does. The file is a best-effort guess, as it's transpiled without the
+/// rest of the module, and is labelled as such.
+pub fn module_error<F>(
+    module_input_path: &path::Path,
+    error: ApiError,
+    module_builder: F,
+) -> CliError
+where
+    F: Fn(&path::Path) -> TranspileModuleBuilder + Sync,
+{
+    let diagnostic = match first_failing_alone(module_input_path, &module_builder) {
+        Ok(Some((python_file, e))) => Diagnostic::new(&e, Some(&python_file)).note(
+            "the module failed and this file fails on its own, the error may differ within the module",
+        ),
+        Ok(None) => Diagnostic::new(&error, None),
+        Err(e) => {
+            warn!("Could not find the file that fails: {}", e);
+            Diagnostic::new(&error, None)
+        }
+    };
+    CliError::Diagnostics(vec![diagnostic])
+}
+
+/// Returns the first file of a module that fails to transpile as a module of
+/// its own, along with its error.
+fn first_failing_alone<F>(
+    module_input_path: &path::Path,
+    module_builder: &F,
+) -> Result<Option<(path::PathBuf, ApiError)>>
+where
+    F: Fn(&path::Path) -> TranspileModuleBuilder + Sync,
+{
+    crate::python_files(module_input_path)?
+        .into_par_iter()
+        .map(|python_file| {
+            let result = transpile_alone(module_input_path, &python_file, module_builder)?;
+            Ok(result.err().map(|e| (python_file, e)))
+        })
+        .find_map_first(Result::transpose)
+        .transpose()
+}
+
+/// Transpiles a file of a module as a module of its own.
+fn transpile_alone<F>(
+    module_input_path: &path::Path,
+    python_file: &path::Path,
+    module_builder: &F,
+) -> Result<std::result::Result<(), ApiError>>
+where
+    F: Fn(&path::Path) -> TranspileModuleBuilder,
+{
+    let staged = StagedModule::new(module_input_path, &[python_file.to_path_buf()])?;
+    Ok(module_builder(&staged.path).transpile().map(drop))
+}
+
+/// Finds the file that makes a module of `python_files` fail with `error`,
+/// ie. the first file that fails when the files are staged in order. Returns
+/// its index and the error of the smallest failing part of the module.
+fn first_failing<F>(
+    module_input_path: &path::Path,
+    python_files: &[path::PathBuf],
+    mut error: ApiError,
+    module_builder: &F,
+) -> Result<(usize, ApiError)>
+where
+    F: Fn(&path::Path) -> TranspileModuleBuilder,
+{
+    // The first `passing` files transpile and the first `failing` files don't.
+    // Each file transpiles on its own, so no file at all passes as well.
+    let (mut passing, mut failing) = (0, python_files.len());
+    while failing - passing > 1 {
+        let middle = (passing + failing) / 2;
+        let staged = StagedModule::new(module_input_path, &python_files[..middle])?;
+        match module_builder(&staged.path).transpile() {
+            Ok(_) => passing = middle,
+            Err(e) => {
+                failing = middle;
+                error = e;
+            }
+        }
+    }
+    Ok((failing - 1, error))
+}
+
+/// Returns `path` relative to `root`, or an error if it's not within `root`.
+fn relative_path<'a>(path: &'a path::Path, root: &path::Path) -> Result<&'a path::Path> {
+    path.strip_prefix(root)
+        .map_err(|_| CliError::NotInModule(path.to_path_buf()))
+}
+
+/// Creates the Rust written for a file that failed to transpile: the Python
+/// source in a comment and a `compile_error!` so that the crate doesn't build
+/// until the file is fixed.
+fn stub(python_path: &path::Path, diagnostic: &Diagnostic, python_source: &str) -> String {
+    let rendered = diagnostic.to_string();
+    let mut stub = format!("// serpent could not transpile {:?}:\n", python_path);
+    let comment_lines = rendered
+        .lines()
+        .chain(iter::once(""))
+        .chain(iter::once("Python source:"))
+        .chain(python_source.lines());
+    for line in comment_lines {
+        if line.is_empty() {
+            stub.push_str("//\n");
+        } else {
+            stub.push_str(&format!("// {}\n", line));
+        }
+    }
+
+    let message = format!(
+        "serpent could not transpile {}: error[{}]: {}",
+        python_path.display(),
+        diagnostic.code,
+        diagnostic.message
+    );
+    stub.push_str(&format!("\ncompile_error!({:?});\n", message));
+    stub
+}
@@ -0,0 +1,63 @@
+//! Three-way merging of regenerated output with hand edits.
+//!
+//! The pristine generated version of each output file is kept in
+//! `OUTPUT/.serpent/base/`. When a file is regenerated, the changes between the
+//! previous and the new generated version are merged into the file on disk,
+//! so that edits made by hand are preserved.
+use super::Result;
+use fs_err as fs;
+
+use std::path;
+
+/// Directory of the pristine generated files, relative to the output directory.
+pub const BASE_DIR: &str = ".serpent/base";
+
+/// The contents to write into an output file.
+pub struct Merge {
+    pub contents: String,
+    /// True if hand edits were merged into the generated contents
+    pub merged: bool,
+    /// True if the contents contain conflict markers
+    pub conflicted: bool,
+}
+
+/// Merges the newly `generated` contents of `out_path` with the hand edits
+/// made since the previous generation recorded in `base_path`.
+pub fn merge(out_path: &path::Path, base_path: &path::Path, generated: &str) -> Result<Merge> {
+    let generated_only = Merge {
+        contents: generated.to_owned(),
+        merged: false,
+        conflicted: false,
+    };
+
+    // Without a base or an existing file, there's nothing to merge with
+    if !out_path.is_file() || !base_path.is_file() {
+        return Ok(generated_only);
+    }
+    let base = fs::read_to_string(base_path)?;
+    let on_disk = fs::read_to_string(out_path)?;
+    if on_disk == base || on_disk == generated {
+        return Ok(generated_only);
+    }
+
+    Ok(match diffy::merge(&base, &on_disk, generated) {
+        Ok(contents) => Merge {
+            contents,
+            merged: true,
+            conflicted: false,
+        },
+        Err(contents) => Merge {
+            contents,
+            merged: true,
+            conflicted: true,
+        },
+    })
+}
+
+/// Returns the path of the pristine copy of `out_path`.
+pub fn base_path(out_path: &path::Path, mod_out_path: &path::Path) -> path::PathBuf {
+    // Unwrap is safe because output files are always within the output directory
+    mod_out_path
+        .join(BASE_DIR)
+        .join(out_path.strip_prefix(mod_out_path).unwrap())
+}
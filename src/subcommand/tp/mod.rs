@@ -1,9 +1,23 @@
 //! Subcommand for transpiling files or modules.
+mod cache;
 mod cargo_util;
+mod check;
+mod keep_going;
+mod merge;
+mod python_deps;
+mod rustfmt;
+mod sarif;
+mod source_map;
+mod stats;
 mod transpile;
+mod watch;
 
+pub use self::keep_going::module_error;
 use self::transpile::*;
+pub use self::transpile::{explain, generate_module};
+use crate::config::{Options, ProjectConfig};
 use crate::error::CliError;
+use crate::message::{self, MessageFormat};
 use crate::{generate_target, TranspileUnit};
 use fs_err as fs;
 use log::info;
@@ -16,19 +30,13 @@ pub type Result<T> = std::result::Result<T, CliError>;
 /// Create the clap subcommand for `tp`.
 pub fn app() -> clap::App<'static, 'static> {
     clap::SubCommand::with_name(name()).alias("tp")
-        .about("Transpiles INPUT which is a file or a module.")
+        .about("Transpiles INPUT which is a file, a module or \"-\" for stdin.")
         .arg(
             clap::Arg::with_name("INPUT")
                 .help("sets the input module or file to transpile")
                 .required(true)
                 .index(1),
         )
-        .arg(
-            clap::Arg::with_name("lines")
-                .long("lines")
-                .short("l")
-                .help("add line numbers to output"),
-        )
         .arg(
             clap::Arg::with_name("output")
                 .long("output")
@@ -39,40 +47,238 @@ pub fn app() -> clap::App<'static, 'static> {
                     "Sets an output file or directory. Needs to be the same kind as INPUT: file for an input file or a directory for an input module.",
                 )
         )
-        .arg(clap::Arg::with_name("omit-manifest").long("omit-manifest").help("omits Cargo.toml manifest from output"))
-        .arg(clap::Arg::with_name("emit-manifest").long("emit-manifest").help("also emits Cargo.toml manifest").conflicts_with("omit-manifest"))
-        .arg(clap::Arg::with_name("remap-file").long("remap-file").short("m").help("sets the toml file to be used for remapping").long_help("Sets the toml file to be used for remapping and dependencies. If omitted, Remap.toml will be auto-detected from INPUT. If not found, no remapping is used."))
-        .arg(clap::Arg::with_name("no-remap").long("no-remap").help("do not auto-detect a Remap.toml").long_help("Explicitly avoid auto-detecting a Remap.toml-file from INPUT.").conflicts_with("remap-file"))
+        .args(&transpile_args())
+        .arg(clap::Arg::with_name("merge").long("merge").help("keeps hand edits in the output directory").long_help("Merges the hand edits made to the output files since the previous transpilation into the regenerated files, instead of overwriting them. The pristine generated files are kept in OUTPUT/.serpent/base and hand edits are preserved with a three-way merge. Conflicting edits are written with conflict markers and fail the run."))
+        .arg(clap::Arg::with_name("no-merge").long("no-merge").help("overwrites hand edits, overriding `merge = true` in the configuration"))
+        .arg(clap::Arg::with_name("check").long("check").help("runs `cargo check` on the output crate").long_help("Runs `cargo check` on the output crate after transpilation and reports each diagnostic along with the Python file and line it originates from. Requires an output directory with a Cargo.toml manifest.").conflicts_with("lines"))
+        .arg(clap::Arg::with_name("source-maps").long("source-maps").help("writes a <name>.rs.map source map next to each output file").long_help("Writes a <name>.rs.map JSON file next to each output file, mapping ranges of Rust lines to the Python file and line they were transpiled from. Requires an output directory.").conflicts_with("lines"))
+        .arg(clap::Arg::with_name("watch").long("watch").help("re-transpiles when INPUT or the remap file changes").long_help("Keeps running and re-transpiles the module whenever a Python file in INPUT or the remap file changes. Only output files whose contents changed are rewritten. Requires an output directory.").conflicts_with("check"))
+        .arg(clap::Arg::with_name("cache").long("cache").help("skips the module if it's unchanged since the previous run").long_help("Keeps a content-hash cache in OUTPUT/.serpent-cache. Skips transpiling the module if no Python file, the remap file nor the configuration changed since the previous run, and otherwise leaves the output files that did not change untouched. Requires an output directory."))
+        .arg(clap::Arg::with_name("jobs").long("jobs").short("j").takes_value(true).help("sets the number of parallel jobs, defaults to the number of CPUs").long_help("Sets the number of parallel jobs used for the work on each file of a module: transpiling each file on its own to find the files that fail, tracing the lines of each file for --check, --source-maps and --sarif, formatting with --fmt and writing the output files. serpent transpiles the module itself in a single pass, as the output of each file depends on the imports of the others. Defaults to the number of CPUs."))
+        .arg(clap::Arg::with_name("keep-going").long("keep-going").help("writes the files that can be transpiled when others fail").long_help("Transpiles every file of the module that can be transpiled and writes its output. A stub with the Python source in a comment and a `compile_error!` is written in place of each file that fails. The run ends with a list of the failed files and an error."))
+        .arg(clap::Arg::with_name("stats").long("stats").value_name("FORMAT").takes_value(true).min_values(0).max_values(1).require_equals(true).possible_values(stats::Format::VALUES).help("prints statistics of the run as text or json").long_help("Prints statistics of the run: the files processed with their kind, Python and Rust line counts, wall time and number of fallbacks, ie. `todo!()` or `unimplemented!()` emitted for constructs that could not be transpiled, and the files that failed. FORMAT is \"text\" (default) or \"json\", eg. --stats=json."))
+        .arg(message::arg())
+        .arg(clap::Arg::with_name("sarif").long("sarif").value_name("FILE").takes_value(true).help("writes the constructs that could not be transpiled into a SARIF file").long_help("Writes a SARIF log for code-scanning dashboards into FILE. Every construct that was transpiled into `todo!()` or `unimplemented!()` is reported at its Python line as a warning, with a rule per kind of construct, eg. SP1000/with. Every file that failed to transpile is reported as an error with the code of its diagnostic. Only the transpiled output is reported, not hand edits merged with --merge. INPUT cannot be read from stdin."))
+        .arg(clap::Arg::with_name("cache-dir").long("cache-dir").takes_value(true).help("enables --cache using the given cache directory"))
+}
+
+/// Creates the arguments that affect the transpiled output, shared with
+/// subcommands that transpile the same way as `tp`.
+pub fn transpile_args() -> Vec<clap::Arg<'static, 'static>> {
+    vec![
+        clap::Arg::with_name("lines")
+            .long("lines")
+            .short("l")
+            .help("add line numbers to output"),
+        clap::Arg::with_name("fmt")
+            .long("fmt")
+            .help("formats the output with rustfmt")
+            .long_help("Formats each output file with the local rustfmt before writing it, honouring the rustfmt.toml of the output crate. If formatting a file fails, a warning is printed and the file is written unformatted."),
+        clap::Arg::with_name("omit-manifest")
+            .long("omit-manifest")
+            .help("omits Cargo.toml manifest from output"),
+        clap::Arg::with_name("edition")
+            .long("edition")
+            .takes_value(true)
+            .possible_values(cargo_util::Edition::VALUES)
+            .help("sets the Rust edition of the emitted manifest")
+            .long_help("Sets the Rust edition of the emitted manifest. Overrides the edition in the [manifest.package] table of the remap file. Defaults to 2018."),
+        clap::Arg::with_name("emit-manifest")
+            .long("emit-manifest")
+            .help("also emits Cargo.toml manifest")
+            .conflicts_with("omit-manifest"),
+        clap::Arg::with_name("remap-file")
+            .long("remap-file")
+            .short("m")
+            .takes_value(true)
+            .help("sets the toml file to be used for remapping")
+            .long_help("Sets the toml file to be used for remapping and dependencies. If omitted, Remap.toml will be auto-detected from INPUT. If not found, no remapping is used."),
+        clap::Arg::with_name("python-deps")
+            .long("python-deps")
+            .takes_value(true)
+            .help("derives crate dependencies from a requirements.txt, pyproject.toml or setup.cfg")
+            .long_help("Derives crate dependencies from the PyPI packages in a requirements.txt, in the [project.dependencies] of a pyproject.toml or in the install_requires of a setup.cfg. Packages are mapped into crates with a built-in table that can be extended with the [pypi] table of the remap file. Dependencies in the remap file take precedence."),
+        clap::Arg::with_name("no-remap")
+            .long("no-remap")
+            .help("do not auto-detect a Remap.toml")
+            .long_help("Explicitly avoid auto-detecting a Remap.toml-file from INPUT.")
+            .conflicts_with("remap-file"),
+    ]
 }
 
 /// Run the behavior of the `tp` subcommand.
-pub fn run(matches: &clap::ArgMatches) -> Result<()> {
+pub fn run(matches: &clap::ArgMatches, project: &ProjectConfig) -> Result<()> {
     // Collect a transpilation config at this point
-    let cfg = resolve_args(matches)?;
-    do_work(&cfg)
+    let cfg = resolve_args(matches, project)?;
+    if cfg.watch {
+        watch::watch(&cfg)
+    } else {
+        do_work(&cfg)
+    }
+}
+
+pub fn resolve_args(matches: &clap::ArgMatches, project: &ProjectConfig) -> Result<Config> {
+    // Options not given on the command line default to the project configuration
+    let opts = Options::new(matches, project, name());
+    let output = opts.value_of("output")?;
+    let output_cfg = resolve_output_args(matches, &opts, output)?;
+    let (target, output) = (&output_cfg.transpile_unit, &output_cfg.output);
+    let line_numbers = output_cfg.line_numbers;
+
+    let jobs = opts
+        .value_of("jobs")?
+        .as_deref()
+        .map(|jobs| match jobs.parse::<usize>() {
+            Ok(jobs) if jobs > 0 => Ok(jobs),
+            _ => Err(CliError::InvalidArgument("jobs", jobs.to_owned())),
+        })
+        .transpose()?;
+
+    let check = opts.is_present("check")?;
+
+    // Assert that check is used only when outputting modules
+    if check {
+        match (&target, &output) {
+            (TranspileUnit::Module(_), Some(TranspileUnit::Module(_))) => {}
+            _ => {
+                return Err(CliError::RequiresOutputDir("check"));
+            }
+        }
+    }
+
+    let source_maps = opts.is_present("source-maps")?;
+
+    // Assert that source maps are used only when outputting modules
+    if source_maps {
+        match (&target, &output) {
+            (TranspileUnit::Module(_), Some(TranspileUnit::Module(_))) => {}
+            _ => {
+                return Err(CliError::RequiresOutputDir("source-maps"));
+            }
+        }
+    }
+
+    let watch = opts.is_present("watch")?;
+
+    // Assert that watch is used only when outputting modules
+    if watch {
+        match (&target, &output) {
+            (TranspileUnit::Module(_), Some(TranspileUnit::Module(_))) => {}
+            _ => {
+                return Err(CliError::RequiresOutputDir("watch"));
+            }
+        }
+    }
+
+    // Clap only catches conflicts between flags on the command line
+    if check && (line_numbers || watch) {
+        return Err(CliError::RedundantParameter(
+            "`check` cannot be used together with `lines` or `watch`".to_owned(),
+        ));
+    }
+    if source_maps && line_numbers {
+        return Err(CliError::RedundantParameter(
+            "`source-maps` cannot be used together with `lines`".to_owned(),
+        ));
+    }
+
+    let stats = match opts.value_of("stats")? {
+        Some(format) => Some(format.parse::<stats::Format>()?),
+        // `--stats` without a value
+        None if matches.is_present("stats") => Some(stats::Format::Text),
+        None => None,
+    };
+
+    let message_format = MessageFormat::from_options(&opts)?;
+
+    let keep_going = opts.is_present("keep-going")?;
+
+    // Assert that keep-going is used only with modules
+    if keep_going && !matches!(target, TranspileUnit::Module(_)) {
+        return Err(CliError::RequiresModule("keep-going"));
+    }
+
+    let sarif = opts.value_of("sarif")?.map(path::PathBuf::from);
+
+    // SARIF results are located in the input files
+    if sarif.is_some() && matches!(target, TranspileUnit::Source(_)) {
+        return Err(CliError::StdinNotSupported("--sarif"));
+    }
+
+    let cache = opts.is_present("cache")?;
+    let cache_dir = match (&output, opts.value_of("cache-dir")?) {
+        (Some(TranspileUnit::Module(_)), Some(dir)) => Some(path::PathBuf::from(dir)),
+        (Some(TranspileUnit::Module(out_path)), None) if cache => {
+            Some(out_path.join(cache::DEFAULT_CACHE_DIR))
+        }
+        (_, None) if !cache => None,
+        _ => {
+            return Err(CliError::RequiresOutputDir("cache"));
+        }
+    };
+
+    Ok(Config {
+        merge: opts.is_present("merge")?,
+        check,
+        source_maps,
+        watch,
+        cache_dir,
+        jobs,
+        keep_going,
+        stats,
+        message_format,
+        sarif,
+        ..output_cfg
+    })
+}
+
+/// Resolves the arguments of `transpile_args` for a subcommand that transpiles
+/// the same way as `tp` into `output`, eg. `diff`. The other keys of the
+/// `[transpile]` table, eg. `watch`, don't apply to it.
+pub fn resolve_transpile_args(
+    matches: &clap::ArgMatches,
+    project: &ProjectConfig,
+    output: &path::Path,
+) -> Result<Config> {
+    let opts = Options::new(matches, project, name());
+    let output = output.to_string_lossy().into_owned();
+    resolve_output_args(matches, &opts, Some(output))
 }
 
-fn resolve_args(matches: &clap::ArgMatches) -> Result<Config> {
+/// Resolves the arguments that affect the transpiled output, ie. those of
+/// `transpile_args`, and leaves the others at their defaults.
+fn resolve_output_args(
+    matches: &clap::ArgMatches,
+    opts: &Options,
+    output: Option<String>,
+) -> Result<Config> {
     // Calling .unwrap() is safe here because "INPUT" is required
     let input = matches.value_of("INPUT").unwrap();
 
     // Generate targets that need to be transpiled to get desired output
     let target = generate_target(input)?;
 
-    let output = matches.value_of("output").map(|out_path| match target {
-        TranspileUnit::File(_) => TranspileUnit::File(path::Path::new(out_path).to_path_buf()),
-        TranspileUnit::Module(_) => TranspileUnit::Module(path::Path::new(out_path).to_path_buf()),
+    let output = output.map(|out_path| match target {
+        TranspileUnit::File(_) | TranspileUnit::Source(_) => {
+            TranspileUnit::File(path::PathBuf::from(out_path))
+        }
+        TranspileUnit::Module(_) => TranspileUnit::Module(path::PathBuf::from(out_path)),
     });
 
-    let line_numbers = matches.is_present("lines");
+    let line_numbers = opts.is_present("lines")?;
+    let fmt = opts.is_present("fmt")?;
     let create_manifest = match (
-        matches.is_present("emit-manifest"),
-        matches.is_present("omit-manifest"),
+        opts.is_present("emit-manifest")?,
+        opts.is_present("omit-manifest")?,
     ) {
         (true, false) => true,
         (false, true) => false,
         (false, false) => false,
-        (true, true) => unreachable!("should be eliminated by clap"),
+        (true, true) => {
+            return Err(CliError::RedundantParameter(
+                "`emit-manifest` and `omit-manifest` cannot be used together".to_owned(),
+            ))
+        }
     };
 
     // Assert that create manifest is used with modules only, and only when
@@ -81,21 +287,25 @@ fn resolve_args(matches: &clap::ArgMatches) -> Result<Config> {
         match (&target, &output) {
             (TranspileUnit::Module(_), Some(TranspileUnit::Module(_))) => {}
             _ => {
-                return Err(CliError::RedundantParameter(
-                    "`omit-manifest` only makes sense when transpiling an input module into an output directory".to_owned(),
-                ));
+                return Err(CliError::RequiresOutputDir("emit-manifest"));
             }
         }
     }
 
+    let edition = opts
+        .value_of("edition")?
+        .as_deref()
+        .map(str::parse::<cargo_util::Edition>)
+        .transpose()?;
+
     let remap_file=
     // Check input for a remap-file
-    if let Some(path) = matches.value_of("remap-file") {
-        let path = crate::to_path(path)?.to_path_buf();
+    if let Some(path) = opts.value_of("remap-file")? {
+        let path = crate::to_path(&path)?.to_path_buf();
         Some(path)
     }
     // else, try to auto-detect a remap-file
-    else if !matches.is_present("no-remap") {
+    else if !opts.is_present("no-remap")? {
         match &target {
             TranspileUnit::File(fpath) => {
                 let fpath = fs::canonicalize(fpath)?;
@@ -117,6 +327,8 @@ fn resolve_args(matches: &clap::ArgMatches) -> Result<Config> {
                     }
                 })
             }
+            // Source from stdin has no location to detect from
+            TranspileUnit::Source(_) => None,
         }
     }
     // else, do not use a remap file
@@ -129,13 +341,31 @@ fn resolve_args(matches: &clap::ArgMatches) -> Result<Config> {
         None => info!("Not using a remap file"),
     }
 
+    let python_deps = match opts.value_of("python-deps")? {
+        Some(path) => Some(crate::to_file_path_buf(&path)?),
+        None => None,
+    };
+
     Ok(Config {
         transpile_unit: target,
         line_numbers,
+        fmt,
         output,
         create_manifest,
         overwrite_manifest: true,
+        edition,
         remap_file,
+        python_deps,
+        merge: false,
+        check: false,
+        source_maps: false,
+        watch: false,
+        cache_dir: None,
+        jobs: None,
+        keep_going: false,
+        stats: None,
+        message_format: MessageFormat::Human,
+        sarif: None,
     })
 }
 
@@ -146,12 +376,79 @@ pub fn name() -> &'static str {
 pub struct Config {
     transpile_unit: TranspileUnit,
     line_numbers: bool,
+    // Should format the output with rustfmt?
+    fmt: bool,
     // The output file or module directory
     output: Option<TranspileUnit>,
     create_manifest: bool,
     // Should overwrite an existing manifest if found?
     overwrite_manifest: bool,
+    // The edition of the emitted manifest, if set explicitly
+    edition: Option<cargo_util::Edition>,
     remap_file: Option<path::PathBuf>,
+    // The Python dependency file to derive crate dependencies from
+    python_deps: Option<path::PathBuf>,
+    // Should merge hand edits into regenerated output files?
+    merge: bool,
+    // Should run `cargo check` on the output crate?
+    check: bool,
+    // Should write a source map next to each output file?
+    source_maps: bool,
+    // Should keep re-transpiling on changes?
+    watch: bool,
+    // Directory for the incremental cache, if caching is enabled
+    cache_dir: Option<path::PathBuf>,
+    // Number of parallel jobs, or None for the number of CPUs
+    jobs: Option<usize>,
+    // Should write the files that can be transpiled when others fail?
+    keep_going: bool,
+    // The format of the statistics to print after each run, if any
+    stats: Option<stats::Format>,
+    // The format of progress messages
+    message_format: MessageFormat,
+    // The SARIF file to report untranspiled constructs and failed files into
+    sarif: Option<path::PathBuf>,
+}
+
+impl Config {
+    /// Returns the files that affect the output of every Python file.
+    fn shared_inputs(&self) -> Vec<&path::Path> {
+        self.remap_file
+            .iter()
+            .chain(&self.python_deps)
+            .map(path::PathBuf::as_path)
+            .collect()
+    }
+
+    /// Returns the options that affect the output of a module, for keying the
+    /// cache on them.
+    fn output_options(&self) -> String {
+        format!(
+            "{:?}",
+            (
+                self.line_numbers,
+                self.fmt,
+                self.create_manifest,
+                self.overwrite_manifest,
+                self.edition,
+                &self.remap_file,
+                &self.python_deps,
+                self.merge,
+                self.keep_going,
+            )
+        )
+    }
+
+    /// Returns true if progress is reported as JSON messages in stdout.
+    fn json_messages(&self) -> bool {
+        self.message_format == MessageFormat::Json
+    }
+
+    /// Returns true if statistics of each file are collected, either for
+    /// printing them or for the summary message.
+    fn collects_stats(&self) -> bool {
+        self.stats.is_some() || self.json_messages()
+    }
 }
 
 /// Detects and returns the path of a file or a directory in the given path
@@ -188,3 +485,19 @@ where
 
     Ok(())
 }
+
+/// Writes `contents` into `path` unless the file already has the same
+/// contents. Returns true if the file was written.
+pub fn write_file_if_changed<P>(path: P, contents: &str) -> Result<bool>
+where
+    P: AsRef<path::Path>,
+{
+    let path = path.as_ref();
+
+    if path.is_file() && fs::read_to_string(path)? == contents {
+        return Ok(false);
+    }
+
+    write_file(path, contents)?;
+    Ok(true)
+}
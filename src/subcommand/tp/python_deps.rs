@@ -0,0 +1,253 @@
+//! Derives crate dependencies from the dependencies of a Python project.
+//!
+//! The PyPI packages listed in a `requirements.txt`, in the
+//! `[project.dependencies]` of a `pyproject.toml` or in the `install_requires`
+//! of a `setup.cfg` are mapped into crates using a built-in table. The table
+//! can be extended or overridden with the `[pypi]` table of the remap file.
+use super::Result;
+use crate::error::CliError;
+use fs_err as fs;
+use log::{debug, warn};
+use toml::{map::Map as TomlMap, Value as TomlValue};
+
+use std::path;
+
+/// PyPI packages and the crates that replace them. Packages without a crate
+/// that serves the same purpose, eg. click, are left out.
+#[rustfmt::skip]
+const PYPI_CRATES: &[(&str, &str, &str)] = &[
+    // PyPI package, crate, version
+    ("numpy", "ndarray", "0.15"),
+    ("regex", "regex", "1"),
+    ("requests", "ureq", "2"),
+    ("httpx", "reqwest", "0.11"),
+    ("pyyaml", "serde_yaml", "0.8"),
+    ("toml", "toml", "0.5"),
+    ("orjson", "serde_json", "1"),
+    ("ujson", "serde_json", "1"),
+    ("python-dateutil", "chrono", "0.4"),
+    ("pytz", "chrono-tz", "0.5"),
+    ("tqdm", "indicatif", "0.16"),
+    ("pillow", "image", "0.23"),
+    ("more-itertools", "itertools", "0.10"),
+];
+
+/// Reads the PyPI packages from a Python dependency file and maps them into a
+/// dependency map for the `[dependencies]` of a manifest.
+///
+/// `extra` maps PyPI packages into tables of crates, eg.
+/// `scipy = { ndarray-linalg = "0.14" }`, and takes precedence over the
+/// built-in table. An empty table maps a package to no crates.
+pub fn dependencies(
+    path: &path::Path,
+    extra: &TomlMap<String, TomlValue>,
+) -> Result<TomlMap<String, TomlValue>> {
+    let contents = fs::read_to_string(path)?;
+    let file_name = path.file_name().map(|name| name.to_string_lossy());
+    let packages = match file_name.as_deref() {
+        Some("pyproject.toml") => pyproject_packages(&contents)?,
+        Some("setup.cfg") => setup_cfg_packages(&contents),
+        _ => requirements_packages(&contents),
+    };
+
+    let mut deps = TomlMap::new();
+    let mut unmapped = vec![];
+    for package in packages {
+        let crates = match extra.iter().find(|(name, _)| normalize(name) == package) {
+            Some((_, TomlValue::Table(crates))) => crates.clone(),
+            Some((_, value)) => return Err(CliError::TomlContentError(value.clone(), "table")),
+            None => {
+                let crates = PYPI_CRATES
+                    .iter()
+                    .filter(|(pypi, _, _)| *pypi == package)
+                    .map(|(_, krate, version)| {
+                        (krate.to_string(), TomlValue::String(version.to_string()))
+                    })
+                    .collect::<TomlMap<_, _>>();
+                if crates.is_empty() {
+                    unmapped.push(package);
+                    continue;
+                }
+                crates
+            }
+        };
+
+        for (krate, spec) in crates {
+            debug!("Mapping PyPI package {:?} into crate {:?}", package, krate);
+            deps.insert(krate, spec);
+        }
+    }
+
+    for package in &unmapped {
+        warn!(
+            "No crate is known for the PyPI package {:?}, map it in the [pypi] table of the remap file",
+            package
+        );
+    }
+
+    Ok(deps)
+}
+
+/// Normalizes a PyPI package name as in PEP 503, eg. "PyYAML" into "pyyaml".
+fn normalize(name: &str) -> String {
+    let mut normalized = String::new();
+    for c in name.trim().chars() {
+        if c == '-' || c == '_' || c == '.' {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            normalized.extend(c.to_lowercase());
+        }
+    }
+    normalized
+}
+
+/// Returns the normalized package name of a requirement specifier, eg.
+/// "numpy" for "NumPy[extra] >= 1.19; python_version > '3.6'".
+fn requirement_name(requirement: &str) -> Option<String> {
+    let name = requirement
+        .trim()
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'))
+        .next()
+        .unwrap_or_default();
+    if name.is_empty() {
+        None
+    } else {
+        Some(normalize(name))
+    }
+}
+
+/// Reads the packages of a `requirements.txt`. Options such as `-r` or `-e` and
+/// bare URLs are skipped, while URLs with a name, eg. "pkg @ https://…" as in
+/// PEP 508, are read by the name.
+fn requirements_packages(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('-') && !is_bare_url(line))
+        .filter_map(requirement_name)
+        .collect()
+}
+
+/// Returns true if a requirement is a URL without a package name, eg.
+/// "git+https://github.com/psf/requests".
+fn is_bare_url(requirement: &str) -> bool {
+    match requirement.find("://") {
+        Some(idx) => !requirement[..idx].contains('@'),
+        None => false,
+    }
+}
+
+/// Reads the packages of the `[project.dependencies]` of a `pyproject.toml`.
+fn pyproject_packages(contents: &str) -> Result<Vec<String>> {
+    let pyproject = contents.parse::<TomlValue>()?;
+    match pyproject
+        .get("project")
+        .and_then(|project| project.get("dependencies"))
+    {
+        Some(TomlValue::Array(requirements)) => Ok(requirements
+            .iter()
+            .filter_map(TomlValue::as_str)
+            .filter_map(requirement_name)
+            .collect()),
+        Some(value) => Err(CliError::TomlContentError(value.clone(), "Array")),
+        None => Ok(vec![]),
+    }
+}
+
+/// Reads the packages of the `install_requires` of the `[options]` section of
+/// a `setup.cfg`.
+fn setup_cfg_packages(contents: &str) -> Vec<String> {
+    let mut packages = vec![];
+    let mut section = "";
+    let mut in_install_requires = false;
+    for line in contents.lines() {
+        let trimmed = line
+            .split(|c| c == '#' || c == ';')
+            .next()
+            .unwrap_or_default()
+            .trim();
+        if trimmed.starts_with('[') {
+            section = trimmed;
+            in_install_requires = false;
+            continue;
+        }
+
+        // Values continue on indented lines
+        let continuation = line.starts_with(char::is_whitespace);
+        if !continuation {
+            in_install_requires = false;
+            if section == "[options]" {
+                let mut key_value = trimmed.splitn(2, '=');
+                if key_value.next().map(str::trim) == Some("install_requires") {
+                    in_install_requires = true;
+                    let value = key_value.next().unwrap_or_default();
+                    packages.extend(value.split(',').filter_map(requirement_name));
+                }
+            }
+        } else if in_install_requires {
+            packages.extend(requirement_name(trimmed));
+        }
+    }
+    packages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_follows_pep_503() {
+        assert_eq!(normalize("PyYAML"), "pyyaml");
+        assert_eq!(normalize(" zope.interface "), "zope-interface");
+        assert_eq!(normalize("typing__extensions"), "typing-extensions");
+        assert_eq!(normalize("a-_.b"), "a-b");
+    }
+
+    #[test]
+    fn requirement_name_skips_extras_versions_and_markers() {
+        assert_eq!(
+            requirement_name("NumPy[extra] >= 1.19; python_version > '3.6'"),
+            Some("numpy".to_owned())
+        );
+        assert_eq!(
+            requirement_name("requests==2.25"),
+            Some("requests".to_owned())
+        );
+        assert_eq!(
+            requirement_name("pkg @ https://example.com/pkg.zip"),
+            Some("pkg".to_owned())
+        );
+        assert_eq!(requirement_name(">=1.0"), None);
+        assert_eq!(requirement_name(""), None);
+    }
+
+    #[test]
+    fn is_bare_url_tells_urls_without_a_name() {
+        assert!(is_bare_url("git+https://github.com/psf/requests"));
+        assert!(is_bare_url("https://user@example.com/pkg.zip"));
+        assert!(!is_bare_url("pkg @ https://example.com/pkg.zip"));
+        assert!(!is_bare_url("pkg@https://example.com/pkg.zip"));
+        assert!(!is_bare_url("numpy>=1.19"));
+    }
+
+    #[test]
+    fn requirements_packages_skips_options_comments_and_bare_urls() {
+        let contents = "\
+# Pinned for CI
+NumPy>=1.19  # fast arrays
+-r dev-requirements.txt
+-e .
+git+https://github.com/psf/requests
+pkg @ https://example.com/pkg.zip
+
+PyYAML
+";
+
+        assert_eq!(
+            requirements_packages(contents),
+            vec!["numpy", "pkg", "pyyaml"]
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! Formats the transpiled Rust with the local `rustfmt`, for `--fmt`.
+use super::{cargo_util::Edition, Result};
+use crate::error::CliError;
+use log::warn;
+
+use std::io::{self, Write};
+use std::{path, process};
+
+/// Names of the rustfmt configuration files, in the order rustfmt looks for
+/// them.
+const CONFIG_FILES: &[&str] = &["rustfmt.toml", ".rustfmt.toml"];
+
+/// Formats the Rust transpiled from `python_path` like `cargo fmt` would in
+/// `crate_path`, honouring its rustfmt.toml. Returns `source` as is with a
+/// warning if rustfmt is not installed or fails.
+pub fn format(
+    source: &str,
+    python_path: &path::Path,
+    crate_path: Option<&path::Path>,
+    edition: Edition,
+) -> String {
+    match rustfmt(source, crate_path, edition) {
+        Ok(formatted) => formatted,
+        Err(e) => {
+            warn!(
+                "Formatting the output of {:?} failed, keeping it unformatted: {}",
+                python_path, e
+            );
+            source.to_owned()
+        }
+    }
+}
+
+/// Returns the rustfmt configuration file in `dir`, if any.
+pub fn config_file(dir: &path::Path) -> Option<path::PathBuf> {
+    CONFIG_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+fn rustfmt(source: &str, crate_path: Option<&path::Path>, edition: Edition) -> Result<String> {
+    let mut command = process::Command::new("rustfmt");
+    // The edition of the crate takes precedence over rustfmt.toml, as with
+    // `cargo fmt`
+    command
+        .args(&["--emit", "stdout", "--edition", &edition.to_string()])
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped());
+    // rustfmt looks for its configuration from the working directory upward
+    if let Some(crate_path) = crate_path.filter(|path| path.is_dir()) {
+        command.current_dir(crate_path);
+    }
+
+    let io_error = |e: io::Error| CliError::RustfmtFailed(e.to_string());
+    let mut child = command.spawn().map_err(io_error)?;
+    // rustfmt reads all of its input before writing, so the input can be
+    // written before reading the output. Unwrap is safe because stdin is piped.
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .map_err(io_error)?;
+    let output = child.wait_with_output().map_err(io_error)?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(CliError::RustfmtFailed(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ))
+    }
+}
@@ -0,0 +1,345 @@
+//! SARIF reports of the Python constructs that could not be transpiled and of
+//! the files that failed, for code-scanning dashboards.
+//!
+//! Each fallback macro in the output, eg. `todo!()`, is reported at the Python
+//! line it was transpiled from, with a rule per kind of construct, eg.
+//! `SP1000/with` for a `with` statement. Failed files are reported with the
+//! code of their diagnostic, eg. `SP0001`.
+use super::{stats::FALLBACK_MACROS, write_file, Result};
+use crate::{
+    diagnostic::{self, Diagnostic},
+    line_map::LineMap,
+};
+use fs_err as fs;
+use serde::Serialize;
+
+use std::{collections::BTreeMap, path};
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+const VERSION: &str = "2.1.0";
+
+/// The prefix of the rules for constructs that were transpiled into a
+/// fallback macro.
+const FALLBACK_RULE: &str = "SP1000";
+
+/// Python statements reported as a kind of construct of their own. Other lines
+/// are reported as expressions.
+#[rustfmt::skip]
+const STATEMENTS: &[&str] = &[
+    "assert", "async", "await", "class", "def", "del", "elif", "else", "except", "finally",
+    "for", "from", "global", "if", "import", "lambda", "nonlocal", "raise", "return", "try",
+    "while", "with", "yield",
+];
+
+#[derive(Serialize)]
+struct Log<'a> {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    version: &'static str,
+    runs: [Run<'a>; 1],
+}
+
+#[derive(Serialize)]
+struct Run<'a> {
+    tool: Tool,
+    results: &'a [SarifResult],
+}
+
+#[derive(Serialize)]
+struct Tool {
+    driver: Driver,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Driver {
+    name: &'static str,
+    version: &'static str,
+    rules: Vec<Rule>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Rule {
+    id: String,
+    short_description: Text,
+}
+
+#[derive(Serialize)]
+struct Text {
+    text: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifResult {
+    rule_id: String,
+    level: &'static str,
+    message: Text,
+    locations: Vec<Location>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    physical_location: PhysicalLocation,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PhysicalLocation {
+    artifact_location: ArtifactLocation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region: Option<Region>,
+}
+
+#[derive(Serialize)]
+struct ArtifactLocation {
+    uri: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Region {
+    start_line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_column: Option<usize>,
+}
+
+/// The results of a transpilation run, written with `--sarif`.
+#[derive(Default)]
+pub struct Report {
+    results: Vec<SarifResult>,
+    /// Descriptions of the rules of the results by rule ID
+    rules: BTreeMap<String, String>,
+}
+
+impl Report {
+    /// Adds a result for each fallback macro in `rust_target`, located at the
+    /// Python line that produced it.
+    pub fn add_fallbacks(
+        &mut self,
+        python_path: &path::Path,
+        rust_path: Option<&path::Path>,
+        rust_target: &str,
+        line_map: &LineMap,
+    ) -> Result<()> {
+        let python_source = fs::read_to_string(python_path)?;
+        let python_lines = python_source.lines().collect::<Vec<_>>();
+        let statement_starts = statement_starts(&python_source);
+
+        for (idx, rust_line) in rust_target.lines().enumerate() {
+            let fallback = match FALLBACK_MACROS.iter().find(|m| rust_line.contains(*m)) {
+                Some(fallback) => fallback.trim_end_matches('('),
+                None => continue,
+            };
+            let python_line = line_map.python_line_for(idx + 1);
+            let code = python_line.and_then(|line| python_lines.get(line - 1));
+            // The line may continue a statement that started on an earlier line
+            let statement = python_line
+                .and_then(|line| statement_starts.get(line - 1))
+                .and_then(|&start| python_lines.get(start));
+            let kind = statement.map_or("expression", |statement| construct_kind(statement));
+
+            let rule_id = format!("{}/{}", FALLBACK_RULE, kind);
+            self.rules
+                .entry(rule_id.clone())
+                .or_insert_with(|| match kind {
+                    "expression" => "Python expression that could not be transpiled".to_owned(),
+                    "decorator" => "Python decorator that could not be transpiled".to_owned(),
+                    kind => format!("Python `{}` statement that could not be transpiled", kind),
+                });
+
+            let emitted_at = match rust_path {
+                Some(rust_path) => format!(" at {}:{}", rust_path.display(), idx + 1),
+                None => String::new(),
+            };
+            let message = match code {
+                Some(code) => format!(
+                    "`{}` could not be transpiled, `{}()` was emitted{}",
+                    code.trim(),
+                    fallback,
+                    emitted_at
+                ),
+                None => format!("Rust line {} was emitted as `{}()`", idx + 1, fallback),
+            };
+            self.results.push(SarifResult {
+                rule_id,
+                level: "warning",
+                message: Text { text: message },
+                locations: vec![location(
+                    python_path,
+                    python_line,
+                    code.map(|code| code.len() - code.trim_start().len() + 1),
+                )],
+            });
+        }
+        Ok(())
+    }
+
+    /// Adds a result for a file that failed to transpile.
+    pub fn add_failure(&mut self, diagnostic: &Diagnostic) {
+        self.rules
+            .entry(diagnostic.code.to_owned())
+            .or_insert_with(|| diagnostic::description(diagnostic.code).to_owned());
+        self.results.push(SarifResult {
+            rule_id: diagnostic.code.to_owned(),
+            level: "error",
+            message: Text {
+                text: diagnostic.message.clone(),
+            },
+            // A module can fail without a file at fault
+            locations: diagnostic
+                .path
+                .iter()
+                .map(|path| location(path, diagnostic.line, diagnostic.column))
+                .collect(),
+        });
+    }
+
+    /// Writes the report into `path` as a SARIF log.
+    pub fn write(self, path: &path::Path) -> Result<()> {
+        let log = Log {
+            schema: SCHEMA,
+            version: VERSION,
+            runs: [Run {
+                tool: Tool {
+                    driver: Driver {
+                        name: "serpent",
+                        version: env!("CARGO_PKG_VERSION"),
+                        rules: self
+                            .rules
+                            .into_iter()
+                            .map(|(id, description)| Rule {
+                                id,
+                                short_description: Text { text: description },
+                            })
+                            .collect(),
+                    },
+                },
+                results: &self.results,
+            }],
+        };
+        write_file(path, &serde_json::to_string_pretty(&log)?)
+    }
+}
+
+/// Returns the kind of the construct that a statement of Python starts with:
+/// the statement, "decorator" or "expression". The keyword after `async` names
+/// the statement, eg. `async def` is a `def` statement.
+fn construct_kind(statement: &str) -> &'static str {
+    let statement = statement.trim_start();
+    if statement.starts_with('@') {
+        return "decorator";
+    }
+    let mut words = statement
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty());
+    let first_word = match words.next() {
+        Some("async") => words.next(),
+        first_word => first_word,
+    };
+    STATEMENTS
+        .iter()
+        .find(|statement| Some(**statement) == first_word)
+        .copied()
+        .unwrap_or("expression")
+}
+
+/// Returns for each line of Python `source` the index of the line that its
+/// statement starts on. A statement continues onto the next line within
+/// brackets and triple-quoted strings and after a backslash.
+fn statement_starts(source: &str) -> Vec<usize> {
+    let mut starts = Vec::<usize>::new();
+    let mut depth = 0usize;
+    // The quote of the string that is open and whether it is triple-quoted
+    let mut string: Option<(u8, bool)> = None;
+    let mut continued = false;
+
+    for (idx, line) in source.lines().enumerate() {
+        let continues = depth > 0 || string.is_some() || continued;
+        let start = starts.last().copied().filter(|_| continues).unwrap_or(idx);
+        starts.push(start);
+
+        continued = false;
+        let bytes = line.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match string {
+                Some((_, _)) if bytes[i] == b'\\' => i += 1,
+                Some((quote, false)) if bytes[i] == quote => string = None,
+                Some((quote, true)) if bytes[i..].starts_with(&[quote; 3]) => {
+                    string = None;
+                    i += 2;
+                }
+                Some(_) => {}
+                None => match bytes[i] {
+                    b'#' => break,
+                    b'(' | b'[' | b'{' => depth += 1,
+                    b')' | b']' | b'}' => depth = depth.saturating_sub(1),
+                    b'"' | b'\'' => {
+                        let quote = bytes[i];
+                        let triple = bytes[i..].starts_with(&[quote; 3]);
+                        string = Some((quote, triple));
+                        if triple {
+                            i += 2;
+                        }
+                    }
+                    b'\\' if i + 1 == bytes.len() => continued = true,
+                    _ => {}
+                },
+            }
+            i += 1;
+        }
+        // Only triple-quoted strings span lines
+        if let Some((_, false)) = string {
+            string = None;
+        }
+    }
+    starts
+}
+
+fn location(path: &path::Path, line: Option<usize>, column: Option<usize>) -> Location {
+    Location {
+        physical_location: PhysicalLocation {
+            // URIs use forward slashes on every platform
+            artifact_location: ArtifactLocation {
+                uri: path.to_string_lossy().replace('\\', "/"),
+            },
+            region: line.map(|start_line| Region {
+                start_line,
+                start_column: column,
+            }),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn construct_kind_names_the_statement() {
+        assert_eq!(construct_kind("    with open(f) as f:"), "with");
+        assert_eq!(construct_kind("async for x in xs:"), "for");
+        assert_eq!(construct_kind("@functools.cache"), "decorator");
+        assert_eq!(construct_kind("if(x):"), "if");
+        assert_eq!(construct_kind("for_each(xs)"), "expression");
+        assert_eq!(construct_kind("x = yield y"), "expression");
+    }
+
+    #[test]
+    fn statement_starts_follow_continuation_lines() {
+        let source = concat!(
+            "with open(\n",
+            "    \"a\",  # (\n",
+            ") as f:\n",
+            "    x = \"\"\"\n",
+            "if y:\"\"\" + \\\n",
+            "        z\n",
+            "print(')')\n",
+        );
+
+        assert_eq!(statement_starts(source), vec![0, 0, 0, 3, 3, 3, 6]);
+    }
+}
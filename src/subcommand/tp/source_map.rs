@@ -0,0 +1,65 @@
+//! Source maps from transpiled Rust files back to their Python sources.
+//!
+//! A source map is written next to each Rust file as `<name>.rs.map`, eg.:
+//!
+//! ```json
+//! {"version":1,"file":"lib.rs","source":"examples/black_scholes/__init__.py",
+//!  "mappings":[{"rust_start":1,"rust_end":3,"python_line":1}]}
+//! ```
+//!
+//! Line numbers are 1-based and `rust_end` is exclusive.
+use super::{write_file_if_changed, Result};
+use crate::line_map::LineMap;
+use serde::Serialize;
+
+use std::path;
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct SourceMap<'a> {
+    version: u32,
+    /// File name of the Rust file
+    file: String,
+    /// Path of the Python source file as given to serpent
+    source: &'a path::Path,
+    mappings: Vec<Mapping>,
+}
+
+#[derive(Serialize)]
+struct Mapping {
+    rust_start: usize,
+    rust_end: usize,
+    python_line: usize,
+}
+
+/// Returns the path of the source map of `rust_path`.
+pub fn map_path(rust_path: &path::Path) -> path::PathBuf {
+    let mut map_path = rust_path.as_os_str().to_owned();
+    map_path.push(".map");
+    map_path.into()
+}
+
+/// Writes the source map of `rust_path` next to it, unless it's unchanged.
+pub fn write(rust_path: &path::Path, python_path: &path::Path, line_map: &LineMap) -> Result<()> {
+    let source_map = SourceMap {
+        version: VERSION,
+        file: rust_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        source: python_path,
+        mappings: line_map
+            .entries()
+            .iter()
+            .map(|entry| Mapping {
+                rust_start: entry.rust_lines.start,
+                rust_end: entry.rust_lines.end,
+                python_line: entry.python_line,
+            })
+            .collect(),
+    };
+
+    write_file_if_changed(map_path(rust_path), &serde_json::to_string(&source_map)?)?;
+    Ok(())
+}
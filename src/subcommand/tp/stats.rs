@@ -0,0 +1,195 @@
+//! Statistics of a transpilation run, printed with `--stats`.
+use super::Result;
+use crate::error::CliError;
+use fs_err as fs;
+use log::info;
+use serde::Serialize;
+
+use std::{fmt, path, str, time};
+
+/// Macros that serpent emits in place of constructs it cannot transpile.
+pub const FALLBACK_MACROS: &[&str] = &["todo!(", "unimplemented!("];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub const VALUES: &'static [&'static str] = &["text", "json"];
+}
+
+impl str::FromStr for Format {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(CliError::InvalidArgument("stats", s.to_owned())),
+        }
+    }
+}
+
+/// Statistics of a single transpiled Python file.
+#[derive(Debug, Serialize)]
+pub struct FileStats {
+    pub path: path::PathBuf,
+    /// The Rust file written for the Python file, if any
+    pub output: Option<path::PathBuf>,
+    /// The kind of a file of a module, see `message::kind_name`, none for
+    /// single files
+    pub kind: Option<&'static str>,
+    pub python_lines: usize,
+    pub rust_lines: usize,
+    /// Number of constructs that were transpiled into a fallback macro
+    pub fallbacks: usize,
+    /// Wall time of the work on the file in milliseconds: transpiling,
+    /// formatting and writing it. serpent transpiles a module in a single
+    /// pass, so for files of a module this leaves out transpiling, which is
+    /// timed for the whole run.
+    pub time_ms: f64,
+}
+
+impl FileStats {
+    /// Collects the statistics of `python_path` transpiled into `rust_target`.
+    /// The time is left for the caller to set.
+    pub fn new(
+        python_path: &path::Path,
+        kind: Option<&'static str>,
+        rust_target: &str,
+        output: Option<&path::Path>,
+    ) -> Result<FileStats> {
+        let python_source = fs::read_to_string(python_path)?;
+        Ok(FileStats {
+            path: python_path.to_path_buf(),
+            output: output.map(path::Path::to_path_buf),
+            kind,
+            python_lines: python_source.lines().count(),
+            rust_lines: rust_target.lines().count(),
+            fallbacks: FALLBACK_MACROS
+                .iter()
+                .map(|fallback| rust_target.matches(fallback).count())
+                .sum(),
+            time_ms: 0.0,
+        })
+    }
+}
+
+/// A file or module that could not be transpiled.
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    pub path: path::PathBuf,
+    pub error: String,
+}
+
+/// Statistics of a transpilation run.
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub files: Vec<FileStats>,
+    pub failed: Vec<Failure>,
+    /// Wall time of transpiling the input in milliseconds
+    pub transpile_ms: f64,
+    /// Wall time of the whole run in milliseconds
+    pub total_ms: f64,
+}
+
+impl Stats {
+    /// Records that `path` could not be transpiled.
+    pub fn fail(&mut self, path: &path::Path, error: &impl fmt::Display) {
+        self.failed.push(Failure {
+            path: path.to_path_buf(),
+            error: error.to_string(),
+        });
+    }
+
+    /// Records that `path` could not be transpiled, with one entry for each
+    /// file located by a diagnostic.
+    pub fn fail_error(&mut self, path: &path::Path, error: &CliError) {
+        match error {
+            CliError::Diagnostics(diagnostics) => {
+                for diagnostic in diagnostics {
+                    let path = diagnostic.path.as_deref().unwrap_or(path);
+                    self.fail(path, &diagnostic.message);
+                }
+            }
+            error => self.fail(path, error),
+        }
+    }
+
+    /// Prints the statistics. JSON goes to stdout unless it is taken by the
+    /// transpiled output.
+    pub fn print(&self, format: Format, stdout_taken: bool) -> Result<()> {
+        match format {
+            Format::Text => self.print_text(),
+            Format::Json if stdout_taken => eprintln!("{}", serde_json::to_string(self)?),
+            Format::Json => println!("{}", serde_json::to_string(self)?),
+        }
+        Ok(())
+    }
+
+    fn print_text(&self) {
+        let count = |kind| {
+            self.files
+                .iter()
+                .filter(|file| file.kind == Some(kind))
+                .count()
+        };
+
+        info!("Transpilation statistics:");
+        info!(
+            "  Files: {} processed, {} failed",
+            self.files.len(),
+            self.failed.len()
+        );
+        info!(
+            "  Kinds: {} lib, {} bin, {} module",
+            count("lib"),
+            count("bin"),
+            count("module")
+        );
+        info!(
+            "  Lines: {} Python, {} Rust",
+            self.files
+                .iter()
+                .map(|file| file.python_lines)
+                .sum::<usize>(),
+            self.files.iter().map(|file| file.rust_lines).sum::<usize>()
+        );
+        info!(
+            "  Fallbacks: {}",
+            self.files.iter().map(|file| file.fallbacks).sum::<usize>()
+        );
+        info!(
+            "  Time: {:.1} ms transpiling, {:.1} ms in total",
+            self.transpile_ms, self.total_ms
+        );
+
+        if !self.files.is_empty() {
+            info!(
+                "  {:<40} {:<6} {:>6} {:>6} {:>9} {:>10}",
+                "File", "Kind", "Python", "Rust", "Fallbacks", "Time (ms)"
+            );
+        }
+        for file in &self.files {
+            info!(
+                "  {:<40} {:<6} {:>6} {:>6} {:>9} {:>10.2}",
+                file.path.display(),
+                file.kind.unwrap_or("-"),
+                file.python_lines,
+                file.rust_lines,
+                file.fallbacks,
+                file.time_ms
+            );
+        }
+        for failure in &self.failed {
+            info!("  Failed: {}: {}", failure.path.display(), failure.error);
+        }
+    }
+}
+
+/// Converts a duration into fractional milliseconds.
+pub fn millis(duration: time::Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
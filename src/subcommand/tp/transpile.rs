@@ -1,74 +1,307 @@
-use super::{cargo_util, write_file, Config, Result};
-use crate::{error::CliError, TranspileUnit};
+use super::{
+    cache,
+    cargo_util::{self, Manifest, Target},
+    check,
+    keep_going::{self, FailedFile},
+    merge, python_deps, rustfmt, sarif, source_map,
+    stats::{self, FileStats, Stats},
+    write_file, write_file_if_changed, Config, Result,
+};
+use crate::message::{self, Message};
+use crate::{diagnostic, error::CliError, line_map::LineMap, SourceFile, TranspileUnit};
 use fs_err as fs;
 use itertools::Itertools;
-use log::{error, info};
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
 use serpent::{
     output::TranspiledFileKind, Transpile, TranspileConfig, TranspileFileBuilder,
     TranspileModuleBuilder, TranspiledFile,
 };
 use toml::{map::Map as TomlMap, value::Value as TomlValue};
 
-use std::path;
+use std::{borrow::Cow, iter, mem, path, time};
 
 pub fn do_work(cfg: &Config) -> Result<()> {
     let t_cfg = TranspileConfig::default();
+    let start = time::Instant::now();
+    let mut stats = Stats::default();
 
-    match &cfg.transpile_unit {
-        TranspileUnit::File(p) => {
-            let transpiled = TranspileFileBuilder::new(p).config(t_cfg).transpile()?;
-            let transpiled = if cfg.line_numbers {
-                add_line_nbs(&transpiled.rust_target)
-            } else {
-                transpiled.rust_target.clone()
-            };
+    let result = match &cfg.transpile_unit {
+        TranspileUnit::File(p) => transpile_file(p, t_cfg, cfg, &mut stats),
+        TranspileUnit::Source(source) => SourceFile::new(source)
+            .and_then(|source_file| transpile_file(source_file.path(), t_cfg, cfg, &mut stats)),
+        TranspileUnit::Module(module_input_path) => {
+            transpile_module(module_input_path, t_cfg, cfg, &mut stats).map(drop)
+        }
+    };
 
-            match &cfg.output {
-                Some(out_file) => {
-                    if let TranspileUnit::File(path) = out_file {
-                        write_file(path, &transpiled)?;
-                    } else {
-                        // Unreachable because we verify that this is a file in `resolve_args`
-                        unreachable!()
-                    }
-                }
-                None => {
-                    info!("Transpile result for {:?}:\n```\n{}\n```", p, transpiled);
-                }
-            }
+    finish(cfg, &result, &mut stats, start)?;
+    result
+}
+
+/// Reports the end of a run, also a failed one: prints the statistics and
+/// writes the failures and a summary as JSON messages.
+pub fn finish<T>(
+    cfg: &Config,
+    result: &Result<T>,
+    stats: &mut Stats,
+    start: time::Instant,
+) -> Result<()> {
+    if let Some(format) = cfg.stats {
+        stats.total_ms = stats::millis(start.elapsed());
+        // Stdout is taken by the messages or by Rust transpiled from stdin
+        let stdout_taken = cfg.json_messages()
+            || (cfg.output.is_none() && matches!(cfg.transpile_unit, TranspileUnit::Source(_)));
+        stats.print(format, stdout_taken)?;
+    }
+    if cfg.json_messages() {
+        if let Err(e) = result {
+            message::emit_failures(e)?;
         }
-        TranspileUnit::Module(module_input_path) => {
-            transpile_module(module_input_path, t_cfg, cfg)?;
+        message::emit(&Message::Summary {
+            success: result.is_ok(),
+            files: stats.files.len(),
+            failed: stats.failed.len(),
+        })?;
+    }
+    Ok(())
+}
+
+fn transpile_file(
+    p: &path::Path,
+    t_cfg: TranspileConfig,
+    cfg: &Config,
+    stats: &mut Stats,
+) -> Result<()> {
+    // Source from stdin is transpiled from a temporary file
+    let display_path = match cfg.transpile_unit {
+        TranspileUnit::Source(_) => path::Path::new("-"),
+        _ => p,
+    };
+
+    if cfg.json_messages() {
+        message::emit(&Message::FileStarted { path: display_path })?;
+    }
+
+    let start = time::Instant::now();
+    let file = match TranspileFileBuilder::new(p).config(t_cfg).transpile() {
+        Ok(content) => TranspiledFile {
+            source_path: p.to_path_buf(),
+            content,
+            kind: TranspiledFileKind::Normal,
+        },
+        Err(e) => {
+            let e = diagnostic::file_error(p, Some(display_path), e);
+            stats.fail_error(display_path, &e);
+            write_error_sarif(&e, cfg)?;
+            return Err(e);
+        }
+    };
+    let file_stats = if cfg.collects_stats() {
+        stats.transpile_ms = stats::millis(start.elapsed());
+        let mut file_stats = FileStats::new(p, None, &file.content.rust_target, None)?;
+        file_stats.path = display_path.to_path_buf();
+        if let Some(TranspileUnit::File(out_path)) = &cfg.output {
+            file_stats.output = Some(out_path.clone());
+        }
+        Some(file_stats)
+    } else {
+        None
+    };
+
+    let rust_target = if cfg.fmt {
+        // An output file is formatted with the configuration next to it
+        let crate_path = match &cfg.output {
+            Some(TranspileUnit::File(out_path)) => out_path.parent(),
+            _ => None,
+        };
+        let edition = cfg.edition.unwrap_or_default();
+        rustfmt::format(&file.content.rust_target, display_path, crate_path, edition)
+    } else {
+        file.content.rust_target.clone()
+    };
+    let transpiled = if cfg.line_numbers {
+        add_line_nbs(&rust_target)
+    } else {
+        rust_target
+    };
+
+    let out_path = match &cfg.output {
+        Some(TranspileUnit::File(path)) => Some(path),
+        // Unreachable because we verify that this is a file in `resolve_args`
+        Some(TranspileUnit::Module(_)) | Some(TranspileUnit::Source(_)) => unreachable!(),
+        None => None,
+    };
+    match out_path {
+        Some(path) => write_file(path, &transpiled)?,
+        // The Rust is written into the message instead
+        None if cfg.json_messages() => {}
+        // Source from stdin is written to stdout as is for use in pipelines
+        None if matches!(cfg.transpile_unit, TranspileUnit::Source(_)) => {
+            println!("{}", transpiled);
+        }
+        None => {
+            info!("Transpile result for {:?}:\n```\n{}\n```", p, transpiled);
         }
     }
+    if let Some(mut file_stats) = file_stats {
+        file_stats.time_ms = stats::millis(start.elapsed());
+        stats.files.push(file_stats);
+    }
+    if let Some(sarif_path) = &cfg.sarif {
+        let unformatted = Some(file.content.rust_target.as_str()).filter(|_| cfg.fmt);
+        let line_map = build_line_map(&file, &transpiled, unformatted)?;
+        let mut report = sarif::Report::default();
+        report.add_fallbacks(
+            p,
+            out_path.map(path::PathBuf::as_path),
+            &transpiled,
+            &line_map,
+        )?;
+        report.write(sarif_path)?;
+    }
+    if cfg.json_messages() {
+        message::emit(&Message::FileTranspiled {
+            input: display_path,
+            output: out_path.map(path::PathBuf::as_path),
+            kind: None,
+            rust: if out_path.is_none() {
+                Some(&transpiled)
+            } else {
+                None
+            },
+        })?;
+    }
     Ok(())
 }
 
+/// Counts of the files produced by a module transpilation.
+#[derive(Debug, Default)]
+pub struct ModuleSummary {
+    /// Files written into the output directory
+    pub written: usize,
+    /// Files left untouched because their contents did not change
+    pub unchanged: usize,
+    /// The output files, or none if the transpilation was skipped because no
+    /// Python file changed
+    pub outputs: Option<Vec<path::PathBuf>>,
+}
+
 pub fn transpile_module(
     path: impl AsRef<path::Path>,
     t_cfg: TranspileConfig,
     cfg: &Config,
-) -> Result<()> {
+    stats: &mut Stats,
+) -> Result<ModuleSummary> {
     let module_input_path = path.as_ref();
 
-    let (deps, remap) = match &cfg.remap_file {
-        Some(f) => {
-            let (deps, remap) = read_remap_file(f)?;
-            (Some(deps), Some(remap))
-        }
-        None => (None, None),
+    let remap_file = load_remap_file(cfg)?;
+
+    // The rustfmt configuration of the output crate affects every output file
+    let rustfmt_config = match &cfg.output {
+        Some(TranspileUnit::Module(out_path)) if cfg.fmt => rustfmt::config_file(out_path),
+        _ => None,
     };
 
-    let mut builder = TranspileModuleBuilder::new(&module_input_path).config(t_cfg);
+    // Skip transpilation entirely if no Python file has changed since the
+    // previous run
+    let mut cache = match &cfg.cache_dir {
+        Some(dir) => Some(cache::Cache::open(
+            dir,
+            &cfg.shared_inputs()
+                .into_iter()
+                .chain(rustfmt_config.as_deref())
+                .collect::<Vec<_>>(),
+            &t_cfg,
+            &cfg.output_options(),
+        )?),
+        None => None,
+    };
+    if let (Some(cache), Some(TranspileUnit::Module(out_path))) = (&cache, &cfg.output) {
+        let manifest_ready =
+            !cfg.create_manifest || cache.is_manifest_fresh(&out_path.join("Cargo.toml"))?;
+        // `check` needs the transpiled module for mapping diagnostics,
+        // statistics need it for counting lines, and source maps and SARIF
+        // reports for mapping lines
+        if manifest_ready
+            && !cfg.check
+            && cfg.stats.is_none()
+            && !cfg.source_maps
+            && cfg.sarif.is_none()
+        {
+            let python_files = crate::python_files(module_input_path)?;
+            // A removed file changes the import map of the others as well
+            let mut all_fresh = cache.file_count() == python_files.len();
+            for python_file in &python_files {
+                let name = cache::relative_name(python_file, module_input_path);
+                if !all_fresh || !cache.is_fresh(&name, &cache.key(python_file)?, out_path) {
+                    all_fresh = false;
+                    break;
+                }
+            }
+            if all_fresh {
+                info!("All {} file(s) are up to date", python_files.len());
+                return Ok(ModuleSummary {
+                    written: 0,
+                    unchanged: python_files.len(),
+                    outputs: None,
+                });
+            }
+        }
+    }
 
-    if let Some(ref dep_map) = deps {
-        builder = builder.set_dep_map(dep_map.clone());
+    if cfg.json_messages() {
+        for python_file in crate::python_files(module_input_path)? {
+            message::emit(&Message::FileStarted { path: &python_file })?;
+        }
     }
-    if let Some(ref remap) = remap {
-        builder = builder.set_remap(remap.clone());
+
+    // serpent transpiles the module in a single pass. The files are
+    // transpiled on their own on a pool of `jobs` threads when looking for
+    // the files that fail, as is the work on each file after the pass.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(cfg.jobs.unwrap_or(0))
+        .build()?;
+
+    let start = time::Instant::now();
+    let builder = |path: &path::Path| module_builder(path, t_cfg.clone(), remap_file.as_ref());
+    let transpiled = builder(module_input_path)
+        .transpile()
+        .map(|transpiled| (transpiled, vec![]))
+        .or_else(|e| {
+            pool.install(|| {
+                if cfg.keep_going {
+                    warn!("{}, transpiling the files that can be transpiled", e);
+                    keep_going::transpile_partial(module_input_path, builder)
+                } else {
+                    Err(keep_going::module_error(module_input_path, e, builder))
+                }
+            })
+        });
+    let (mut transpiled, failed) = match transpiled {
+        Ok((transpiled, failed)) => (transpiled, failed),
+        Err(e) => {
+            stats.fail_error(module_input_path, &e);
+            write_error_sarif(&e, cfg)?;
+            return Err(e);
+        }
+    };
+    stats.transpile_ms = stats::millis(start.elapsed());
+    for failed_file in &failed {
+        stats.fail(&failed_file.path, &failed_file.diagnostic.message);
     }
+    let mut summary = ModuleSummary::default();
 
-    let mut transpiled = builder.transpile()?;
+    // The unformatted output is kept for mapping lines
+    let (unformatted, format_times) = if cfg.fmt {
+        let crate_path = match &cfg.output {
+            Some(TranspileUnit::Module(out_path)) => Some(out_path.as_path()),
+            _ => None,
+        };
+        pool.install(|| format_files(transpiled.files_mut(), crate_path, cfg))
+    } else {
+        (vec![], vec![])
+    };
 
     // Add line numbers if necessary
     transpiled.files_mut().iter_mut().for_each(|file| {
@@ -80,7 +313,7 @@ pub fn transpile_module(
     // Output module in a directory
     if let Some(output) = &cfg.output {
         let out_path = match output {
-            TranspileUnit::File(_) => {
+            TranspileUnit::File(_) | TranspileUnit::Source(_) => {
                 // Unreachable because we verify that this is a module in `resolve_args`
                 unreachable!()
             }
@@ -97,45 +330,196 @@ pub fn transpile_module(
             fs::create_dir(src_out_path)?;
         }
 
-        let mut bin_target = None;
-        let mut lib_target = None;
+        let lib_name = lib_name(mod_out_path, remap_file.as_ref(), cfg)?;
+        let plan = plan_outputs(
+            transpiled.files(),
+            &failed,
+            module_input_path,
+            mod_out_path,
+            &lib_name,
+        )?;
+        summary.outputs = Some(plan.jobs.iter().map(|job| job.out_path.clone()).collect());
 
-        // Translate output file names and output
-        for TranspiledFile {
-            source_path: in_path,
-            content: transpiled,
-            kind,
-        } in transpiled.files()
-        {
-            let mut out_path = translate(in_path, module_input_path, mod_out_path);
+        // Output into files in parallel. The results are collected in order, so
+        // that the log stays deterministic.
+        let results = pool.install(|| {
+            plan.jobs
+                .par_iter()
+                .map(|job| {
+                    let start = time::Instant::now();
+                    let result =
+                        write_output(job, cache.as_ref(), module_input_path, mod_out_path, cfg);
+                    (result, start.elapsed())
+                })
+                .collect::<Vec<_>>()
+        });
 
-            // Replace special file paths if detected
-            match kind {
-                TranspiledFileKind::LibRs => {
-                    out_path.set_file_name("lib.rs");
-                    lib_target = Some("src/lib.rs");
-                }
-                TranspiledFileKind::MainRs => {
-                    out_path.set_file_name("main.rs");
-                    bin_target = Some("src/main.rs");
+        // Files that were removed or failed are not recorded again
+        if let Some(cache) = &mut cache {
+            cache.clear();
+        }
+        let mut conflicts = vec![];
+        let mut merged_contents = vec![];
+        for (idx, (job, (result, elapsed))) in plan.jobs.iter().zip(results).enumerate() {
+            let outcome = match result {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    stats.fail(job.in_path, &e);
+                    return Err(e);
                 }
-                _ => {}
             };
+            if cfg.collects_stats() && !job.stub {
+                let mut file_stats = FileStats::new(
+                    job.in_path,
+                    job.kind.map(message::kind_name),
+                    &job.contents,
+                    Some(&job.out_path),
+                )?;
+                let format_time = format_times.get(idx).copied().unwrap_or_default();
+                file_stats.time_ms = stats::millis(format_time + elapsed);
+                stats.files.push(file_stats);
+            }
+            if job.stub {
+                if !cfg.json_messages() {
+                    info!("Wrote a stub for {:?} into {:?}", job.in_path, job.out_path);
+                }
+            } else {
+                if cfg.json_messages() {
+                    message::emit(&Message::FileTranspiled {
+                        input: job.in_path,
+                        output: Some(&job.out_path),
+                        kind: job.kind.map(message::kind_name),
+                        rust: None,
+                    })?;
+                } else if outcome.written {
+                    info!("Transpiled {:?} into {:?}", job.in_path, job.out_path);
+                }
+                if outcome.written {
+                    summary.written += 1;
+                } else {
+                    debug!("{:?} is unchanged", job.out_path);
+                    summary.unchanged += 1;
+                }
+            }
+            if outcome.conflicted {
+                conflicts.push(&job.out_path);
+            } else if outcome.merged {
+                info!("Merged hand edits into {:?}", job.out_path);
+            }
+            if let (Some(cache), Some((name, key))) = (&mut cache, outcome.cache_entry) {
+                cache.update(name, key, cache::relative_name(&job.out_path, mod_out_path));
+            }
+            merged_contents.push(outcome.merged_contents);
+        }
+        // The contents of each file as written, with any hand edits merged
+        let written_contents = plan
+            .jobs
+            .iter()
+            .zip(&merged_contents)
+            .map(|(job, merged)| merged.as_deref().unwrap_or(&job.contents[..]))
+            .collect::<Vec<_>>();
+
+        // Map the Rust lines back to Python lines for `cargo check` output,
+        // source maps and SARIF. Lines are mapped as written, so that the maps
+        // account for merged hand edits. Stubs of failed files come last in the
+        // plan and are left out by `zip`.
+        let line_maps = if cfg.check || cfg.source_maps || cfg.sarif.is_some() {
+            pool.install(|| {
+                transpiled
+                    .files()
+                    .par_iter()
+                    .zip(&written_contents)
+                    .enumerate()
+                    .map(|(idx, (file, contents))| {
+                        let unformatted = unformatted.get(idx).map(String::as_str);
+                        build_line_map(file, contents, unformatted)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })?
+        } else {
+            vec![]
+        };
+
+        if cfg.source_maps {
+            for (job, line_map) in plan.jobs.iter().zip(&line_maps) {
+                debug!("Writing source map for {:?}", job.out_path);
+                source_map::write(&job.out_path, job.in_path, line_map)?;
+            }
+        }
 
-            // Output into file
-            info!("Transpiled {:?} into {:?}", &in_path, &out_path);
-            write_file(out_path, &transpiled.rust_target)?;
+        // Only the transpiled output is reported, as hand edits may well add
+        // their own `todo!()`
+        if let Some(sarif_path) = &cfg.sarif {
+            let mut report = sarif::Report::default();
+            for (idx, (file, job)) in transpiled.files().iter().zip(&plan.jobs).enumerate() {
+                let generated_map;
+                let line_map = if merged_contents[idx].is_some() {
+                    let unformatted = unformatted.get(idx).map(String::as_str);
+                    generated_map = build_line_map(file, &job.contents, unformatted)?;
+                    &generated_map
+                } else {
+                    &line_maps[idx]
+                };
+                report.add_fallbacks(job.in_path, Some(&job.out_path), &job.contents, line_map)?;
+            }
+            failed
+                .iter()
+                .for_each(|f| report.add_failure(&f.diagnostic));
+            report.write(sarif_path)?;
+        }
+
+        // Remember where the files came from for mapping `cargo check` output
+        let mut origins = vec![];
+        if cfg.check {
+            for (job, line_map) in plan.jobs.iter().zip(line_maps) {
+                origins.push(check::Origin {
+                    // Unwrap is safe because `translate` places files in `mod_out_path`
+                    rust_path: job
+                        .out_path
+                        .strip_prefix(mod_out_path)
+                        .unwrap()
+                        .to_path_buf(),
+                    python_path: job.in_path.clone(),
+                    line_map,
+                });
+            }
         }
 
         // Create a manifest
         if cfg.create_manifest {
-            cargo_util::create_manifest(
-                &mod_out_path,
-                cfg.overwrite_manifest,
-                deps.as_ref(),
-                bin_target,
-                lib_target,
-            )?;
+            let manifest = build_manifest(mod_out_path, &plan, remap_file.as_ref(), cfg)?;
+            let written =
+                cargo_util::create_manifest(&mod_out_path, cfg.overwrite_manifest, &manifest)?;
+            match written {
+                Some(path) if cfg.json_messages() => {
+                    message::emit(&Message::ManifestWritten { path: &path })?
+                }
+                Some(path) => info!("Wrote manifest into {:?}", path),
+                None => {}
+            }
+            if let Some(cache) = &mut cache {
+                cache.update_manifest(&mod_out_path.join("Cargo.toml"))?;
+            }
+        }
+
+        if let Some(cache) = &cache {
+            cache.save()?;
+        }
+
+        // Report conflicts and failures once everything else is written
+        for path in &conflicts {
+            warn!("Conflicting hand edits in {:?}", path);
+        }
+        report_failures(&failed, cfg)?;
+        if !conflicts.is_empty() {
+            return Err(CliError::MergeConflicts(conflicts.len()));
+        }
+        if !failed.is_empty() {
+            return Err(CliError::FilesFailed(failed.len()));
+        }
+
+        if cfg.check {
+            check::check_crate(&mod_out_path, &origins)?;
         }
     }
     // Output in terminal
@@ -143,22 +527,782 @@ pub fn transpile_module(
         for TranspiledFile {
             source_path: path,
             content: transpiled,
-            ..
+            kind,
         } in transpiled.files()
         {
+            if cfg.json_messages() {
+                message::emit(&Message::FileTranspiled {
+                    input: path,
+                    output: None,
+                    kind: Some(message::kind_name(kind)),
+                    rust: Some(&transpiled.rust_target),
+                })?;
+            } else {
+                println!(
+                    "Transpile result for {:?} in {:?}:\n```\n{}\n```",
+                    module_input_path, path, transpiled.rust_target
+                );
+            }
+            if cfg.collects_stats() {
+                stats.files.push(FileStats::new(
+                    path,
+                    Some(message::kind_name(kind)),
+                    &transpiled.rust_target,
+                    None,
+                )?);
+            }
+        }
+        if let Some(sarif_path) = &cfg.sarif {
+            let mut report = sarif::Report::default();
+            for (idx, file) in transpiled.files().iter().enumerate() {
+                let unformatted = unformatted.get(idx).map(String::as_str);
+                let line_map = build_line_map(file, &file.content.rust_target, unformatted)?;
+                report.add_fallbacks(
+                    &file.source_path,
+                    None,
+                    &file.content.rust_target,
+                    &line_map,
+                )?;
+            }
+            failed
+                .iter()
+                .for_each(|f| report.add_failure(&f.diagnostic));
+            report.write(sarif_path)?;
+        }
+        for failed_file in failed.iter().filter(|_| !cfg.json_messages()) {
             println!(
                 "Transpile result for {:?} in {:?}:\n```\n{}\n```",
-                module_input_path, path, transpiled.rust_target
+                module_input_path, failed_file.path, failed_file.stub
             );
         }
+        report_failures(&failed, cfg)?;
+        if !failed.is_empty() {
+            return Err(CliError::FilesFailed(failed.len()));
+        }
     }
 
+    Ok(summary)
+}
+
+/// Writes the failures of a transpilation that failed with `error` into the
+/// SARIF log, if there is one.
+fn write_error_sarif(error: &CliError, cfg: &Config) -> Result<()> {
+    if let (Some(sarif_path), CliError::Diagnostics(diagnostics)) = (&cfg.sarif, error) {
+        let mut report = sarif::Report::default();
+        diagnostics.iter().for_each(|d| report.add_failure(d));
+        report.write(sarif_path)?;
+    }
     Ok(())
 }
 
-fn read_remap_file(
-    path: impl AsRef<path::Path>,
-) -> Result<(TomlMap<String, TomlValue>, TomlMap<String, TomlValue>)> {
+/// Lists the files that failed to transpile with `keep-going`.
+fn report_failures(failed: &[FailedFile], cfg: &Config) -> Result<()> {
+    for failed_file in failed {
+        if cfg.json_messages() {
+            message::emit(&Message::failed(&failed_file.diagnostic))?;
+        } else {
+            error!(
+                "Failed to transpile {:?}:\n{}",
+                failed_file.path, failed_file.diagnostic
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The contents of a remap file.
+struct RemapFile {
+    /// The `[dependencies]` table
+    deps: TomlMap<String, TomlValue>,
+    /// The optional `[manifest]` table, with extra contents for Cargo.toml
+    manifest: TomlMap<String, TomlValue>,
+    /// The optional `[pypi]` table, mapping PyPI packages into crates
+    pypi: TomlMap<String, TomlValue>,
+    /// All other tables
+    remaps: TomlMap<String, TomlValue>,
+}
+
+/// A transpiled module held in memory, as it would be written into an output
+/// directory.
+pub struct GeneratedModule {
+    /// Output files relative to the output directory, in transpilation order
+    pub files: Vec<(path::PathBuf, String)>,
+    /// Contents of Cargo.toml, if a manifest is emitted
+    pub manifest: Option<String>,
+}
+
+/// Transpiles the input module into memory as it would be written into the
+/// output directory by `transpile_module`.
+pub fn generate_module(cfg: &Config) -> Result<GeneratedModule> {
+    let (module_input_path, mod_out_path) = match (&cfg.transpile_unit, &cfg.output) {
+        (TranspileUnit::Module(input), Some(TranspileUnit::Module(output))) => (input, output),
+        _ => {
+            return Err(CliError::RedundantParameter(
+                "comparing output only makes sense for an input module and an output directory"
+                    .to_owned(),
+            ))
+        }
+    };
+    let t_cfg = TranspileConfig::default();
+
+    let remap_file = load_remap_file(cfg)?;
+
+    let mut transpiled = module_builder(module_input_path, t_cfg.clone(), remap_file.as_ref())
+        .transpile()
+        .map_err(|e| {
+            keep_going::module_error(module_input_path, e, |path| {
+                module_builder(path, t_cfg.clone(), remap_file.as_ref())
+            })
+        })?;
+    if cfg.fmt {
+        format_files(transpiled.files_mut(), Some(mod_out_path), cfg);
+    }
+    if cfg.line_numbers {
+        transpiled.files_mut().iter_mut().for_each(|file| {
+            file.content.rust_target = add_line_nbs(&file.content().rust_target);
+        });
+    }
+
+    let lib_name = lib_name(mod_out_path, remap_file.as_ref(), cfg)?;
+    let plan = plan_outputs(
+        transpiled.files(),
+        &[],
+        module_input_path,
+        mod_out_path,
+        &lib_name,
+    )?;
+    let manifest = if cfg.create_manifest {
+        let manifest = build_manifest(mod_out_path, &plan, remap_file.as_ref(), cfg)?;
+        Some(manifest.to_toml()?)
+    } else {
+        None
+    };
+    let files = plan
+        .jobs
+        .into_iter()
+        .map(|job| {
+            // Unwrap is safe because `translate` places files in `mod_out_path`
+            let relative = job
+                .out_path
+                .strip_prefix(mod_out_path)
+                .unwrap()
+                .to_path_buf();
+            (relative, job.contents.into_owned())
+        })
+        .collect();
+
+    Ok(GeneratedModule { files, manifest })
+}
+
+/// The Python line that produced a line of a transpiled Rust file.
+pub struct Explanation {
+    /// The Python file that was transpiled into the Rust file
+    pub python_path: path::PathBuf,
+    pub python_line: usize,
+    /// The four transpilation steps of the Python line
+    pub trace: Vec<String>,
+}
+
+/// Transpiles the input module into memory and finds the Python line that
+/// produced line `rust_line` of `rust_path`, a file in the output directory
+/// `mod_out_path`.
+pub fn explain(
+    cfg: &Config,
+    mod_out_path: &path::Path,
+    rust_path: &path::Path,
+    rust_line: usize,
+) -> Result<Explanation> {
+    let module_input_path = match &cfg.transpile_unit {
+        TranspileUnit::Module(input) => input,
+        _ => {
+            return Err(CliError::RedundantParameter(
+                "explaining output only makes sense for an input module".to_owned(),
+            ))
+        }
+    };
+    let t_cfg = TranspileConfig::default();
+
+    let remap_file = load_remap_file(cfg)?;
+    let mut transpiled = module_builder(module_input_path, t_cfg.clone(), remap_file.as_ref())
+        .transpile()
+        .map_err(|e| {
+            keep_going::module_error(module_input_path, e, |path| {
+                module_builder(path, t_cfg.clone(), remap_file.as_ref())
+            })
+        })?;
+    // Formatting moves lines, so the output is formatted as it was written.
+    // The unformatted output is kept for mapping lines.
+    let unformatted = if cfg.fmt {
+        format_files(transpiled.files_mut(), Some(mod_out_path), cfg).0
+    } else {
+        vec![]
+    };
+
+    // Invert `translate` by finding the file that is written into `rust_path`.
+    // Line numbers are left out, as they don't move any lines.
+    let lib_name = lib_name(mod_out_path, remap_file.as_ref(), cfg)?;
+    let plan = plan_outputs(
+        transpiled.files(),
+        &[],
+        module_input_path,
+        mod_out_path,
+        &lib_name,
+    )?;
+    let relative = |out_path: &path::Path| {
+        out_path
+            .strip_prefix(mod_out_path)
+            .ok()
+            .map(path::Path::to_path_buf)
+    };
+    let wanted = relative(rust_path);
+    let (idx, (file, job)) = transpiled
+        .files()
+        .iter()
+        .zip(&plan.jobs)
+        .enumerate()
+        .find(|(_, (_, job))| wanted.is_some() && relative(&job.out_path) == wanted)
+        .ok_or_else(|| CliError::NotInModule(rust_path.to_path_buf()))?;
+
+    // Map the lines of the file as written, which may have hand edits merged
+    // into it. Line numbers keep the lines from matching, but don't move them.
+    let on_disk = fs::read_to_string(rust_path).ok();
+    let rust_target = match &on_disk {
+        Some(on_disk) if !cfg.line_numbers => on_disk.as_str(),
+        Some(on_disk) => {
+            if on_disk.lines().count() != job.contents.lines().count() {
+                warn!(
+                    "{:?} differs from the transpiled output, line {} may have moved",
+                    rust_path, rust_line
+                );
+            }
+            &job.contents[..]
+        }
+        None => &job.contents[..],
+    };
+
+    let no_python_line = || CliError::NoPythonLine(rust_path.to_path_buf(), rust_line);
+    if rust_line == 0 || rust_line > rust_target.lines().count() {
+        return Err(no_python_line());
+    }
+    let line_map = build_line_map(file, rust_target, unformatted.get(idx).map(String::as_str))?;
+    let python_line = line_map
+        .python_line_for(rust_line)
+        .ok_or_else(no_python_line)?;
+
+    Ok(Explanation {
+        python_path: job.in_path.clone(),
+        python_line,
+        trace: file.trace_steps_for_line(python_line, false)?,
+    })
+}
+
+/// Creates a module builder configured with the contents of a remap file.
+fn module_builder(
+    module_input_path: &path::Path,
+    t_cfg: TranspileConfig,
+    remap_file: Option<&RemapFile>,
+) -> TranspileModuleBuilder {
+    let mut builder = TranspileModuleBuilder::new(&module_input_path).config(t_cfg);
+
+    if let Some(remap_file) = remap_file {
+        builder = builder
+            .set_dep_map(remap_file.deps.clone())
+            .set_remap(remap_file.remaps.clone());
+    }
+
+    builder
+}
+
+/// Where a file is placed in the output directory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placement {
+    /// The library root, src/lib.rs
+    Lib,
+    /// A binary target in src/bin/
+    Bin,
+    /// A module of the library
+    Module,
+}
+
+impl Placement {
+    fn of(kind: &TranspiledFileKind) -> Placement {
+        match kind {
+            TranspiledFileKind::LibRs => Placement::Lib,
+            TranspiledFileKind::MainRs => Placement::Bin,
+            _ => Placement::Module,
+        }
+    }
+
+    /// Places a file that failed to transpile as serpent would have placed it.
+    fn of_failed(failed_file: &FailedFile, module_input_path: &path::Path) -> Placement {
+        if failed_file.path == module_input_path.join("__init__.py") {
+            Placement::Lib
+        } else if failed_file.entry_point {
+            Placement::Bin
+        } else {
+            Placement::Module
+        }
+    }
+}
+
+/// A transpiled file to be written into the output directory.
+struct OutputJob<'t> {
+    in_path: &'t path::PathBuf,
+    out_path: path::PathBuf,
+    /// Borrowed from the transpiled file, unless paths were rewritten into it
+    contents: Cow<'t, str>,
+    /// The kind serpent gave the file, none for stubs
+    kind: Option<&'t TranspiledFileKind>,
+    placement: Placement,
+    /// True for the stub of a file that failed to transpile
+    stub: bool,
+}
+
+/// The files and targets of a module in the output directory.
+struct OutputPlan<'t> {
+    jobs: Vec<OutputJob<'t>>,
+    lib_target: Option<Target>,
+    bin_targets: Vec<Target>,
+}
+
+impl<'t> OutputPlan<'t> {
+    /// Translates the path of a file into a path in the output directory and
+    /// adds its target, if any.
+    fn place(
+        &mut self,
+        in_path: &path::Path,
+        placement: Placement,
+        module_input_path: &path::Path,
+        mod_out_path: &path::Path,
+        lib_name: &str,
+    ) -> path::PathBuf {
+        let mut out_path = translate(in_path, module_input_path, mod_out_path);
+
+        // Replace special file paths if detected
+        match placement {
+            Placement::Lib => {
+                out_path.set_file_name("lib.rs");
+                self.lib_target = Some(Target {
+                    name: lib_name.to_owned(),
+                    path: "src/lib.rs".to_owned(),
+                });
+            }
+            Placement::Bin => {
+                // Each entry point becomes its own binary target
+                let name = bin_name(in_path, module_input_path);
+                out_path = mod_out_path
+                    .join("src")
+                    .join("bin")
+                    .join(format!("{}.rs", name));
+                self.bin_targets
+                    .push(Target::from_path(format!("src/bin/{}.rs", name)));
+            }
+            Placement::Module => {}
+        };
+
+        out_path
+    }
+}
+
+/// Translates the paths of transpiled files and the stubs of failed files into
+/// paths in the output directory and collects the library and binary targets.
+/// The library is named `lib_name`. The stubs come after the transpiled files.
+fn plan_outputs<'t>(
+    files: impl IntoIterator<Item = &'t TranspiledFile>,
+    failed: &'t [FailedFile],
+    module_input_path: &path::Path,
+    mod_out_path: &path::Path,
+    lib_name: &str,
+) -> Result<OutputPlan<'t>> {
+    let mut plan = OutputPlan {
+        jobs: vec![],
+        lib_target: None,
+        bin_targets: vec![],
+    };
+
+    for file in files {
+        let TranspiledFile {
+            source_path: in_path,
+            content: transpiled,
+            kind,
+        } = file;
+        let placement = Placement::of(kind);
+        let out_path = plan.place(
+            in_path,
+            placement,
+            module_input_path,
+            mod_out_path,
+            lib_name,
+        );
+
+        plan.jobs.push(OutputJob {
+            in_path,
+            out_path,
+            contents: Cow::Borrowed(&transpiled.rust_target),
+            kind: Some(kind),
+            placement,
+            stub: false,
+        });
+    }
+
+    for failed_file in failed {
+        let placement = Placement::of_failed(failed_file, module_input_path);
+        let out_path = plan.place(
+            &failed_file.path,
+            placement,
+            module_input_path,
+            mod_out_path,
+            lib_name,
+        );
+
+        plan.jobs.push(OutputJob {
+            in_path: &failed_file.path,
+            out_path,
+            contents: Cow::Borrowed(&failed_file.stub),
+            kind: None,
+            placement,
+            stub: true,
+        });
+    }
+
+    // Binary targets are crate roots of their own, so their paths into the
+    // module must go through the library
+    let has_lib = plan.lib_target.is_some();
+    for job in &mut plan.jobs {
+        if job.placement != Placement::Bin {
+            continue;
+        }
+        if has_lib {
+            job.contents = Cow::Owned(paths_into_lib(&job.contents, lib_name));
+        } else if declares_modules(&job.contents) {
+            return Err(CliError::ModulesWithoutLib(job.in_path.to_path_buf()));
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Rewrites the paths of a binary target to refer to the library `lib_name`:
+/// `crate::` in a binary refers to the binary itself and `mod x;` looks for
+/// src/bin/x.rs, while the modules are part of the library. Only code is
+/// rewritten, not comments and literals. Each line is rewritten in place, so
+/// that no lines move.
+fn paths_into_lib(contents: &str, lib_name: &str) -> String {
+    let mask = code_mask(contents);
+    let mut rewritten = String::with_capacity(contents.len());
+    for (idx, (line, is_code)) in code_lines(contents, &mask).enumerate() {
+        if idx > 0 {
+            rewritten.push('\n');
+        }
+
+        let code = code_prefix(line, is_code);
+        if let Some((vis, module)) = module_decl(code) {
+            let indent = &line[..line.len() - line.trim_start().len()];
+            let decl_len = code.trim_end().len();
+            rewritten.push_str(&format!("{}{}use {}::{};", indent, vis, lib_name, module));
+            rewritten.push_str(&line[decl_len..]);
+            continue;
+        }
+
+        // `$crate::` in macros and paths such as `my_crate::` are left as is
+        let mut copied = 0;
+        for (pos, path) in line.match_indices("crate::") {
+            let preceding = line[..pos].chars().next_back();
+            if is_code[pos] && !preceding.map_or(false, |c| is_ident_char(c) || c == '$') {
+                rewritten.push_str(&line[copied..pos]);
+                rewritten.push_str(lib_name);
+                rewritten.push_str("::");
+                copied = pos + path.len();
+            }
+        }
+        rewritten.push_str(&line[copied..]);
+    }
+    rewritten
+}
+
+/// Returns true if `contents` declares modules in files, ie. `mod x;`.
+fn declares_modules(contents: &str) -> bool {
+    let mask = code_mask(contents);
+    let mut lines = code_lines(contents, &mask);
+    lines.any(|(line, is_code)| module_decl(code_prefix(line, is_code)).is_some())
+}
+
+/// Returns the start of `line` up to its first comment or literal.
+fn code_prefix<'a>(line: &'a str, is_code: &[bool]) -> &'a str {
+    let code_len = is_code
+        .iter()
+        .position(|code| !code)
+        .unwrap_or_else(|| line.len());
+    &line[..code_len]
+}
+
+/// Returns the visibility and the name of the module declared by `code`, the
+/// code of a line up to its first comment or literal, if it is `[pub ]mod x;`.
+fn module_decl(code: &str) -> Option<(&str, &str)> {
+    let code = code.trim();
+    let (vis, decl) = match code.strip_prefix("pub ") {
+        Some(decl) => ("pub ", decl),
+        None => ("", code),
+    };
+    decl.strip_prefix("mod ")
+        .and_then(|decl| decl.strip_suffix(';'))
+        .map(str::trim)
+        .filter(|module| !module.is_empty() && module.chars().all(is_ident_char))
+        .map(|module| (vis, module))
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Splits Rust `source` into lines, each with the part of `mask` that tells
+/// which of its bytes are code.
+fn code_lines<'a>(
+    source: &'a str,
+    mask: &'a [bool],
+) -> impl Iterator<Item = (&'a str, &'a [bool])> + 'a {
+    let mut start = 0;
+    source.split('\n').map(move |line| {
+        let is_code = &mask[start..start + line.len()];
+        start += line.len() + 1;
+        (line, is_code)
+    })
+}
+
+/// Returns for each byte of Rust `source` whether it is code, ie. not within a
+/// comment, a string or a character literal.
+fn code_mask(source: &str) -> Vec<bool> {
+    let bytes = source.as_bytes();
+    let len = bytes.len();
+    let mut mask = vec![true; len];
+    let mut idx = 0;
+    while idx < len {
+        let start = idx;
+        let rest = &bytes[idx..];
+        if rest.starts_with(b"//") {
+            idx += rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+        } else if rest.starts_with(b"/*") {
+            // Block comments nest
+            let mut depth = 0;
+            while idx < len {
+                if bytes[idx..].starts_with(b"/*") {
+                    depth += 1;
+                    idx += 2;
+                } else if bytes[idx..].starts_with(b"*/") {
+                    depth -= 1;
+                    idx += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    idx += 1;
+                }
+            }
+        } else if let Some(hashes) = raw_string_hashes(bytes, idx) {
+            let body = idx + hashes + 2;
+            let closing = iter::once(b'"')
+                .chain(iter::repeat(b'#').take(hashes))
+                .collect::<Vec<_>>();
+            idx = bytes[body..]
+                .windows(closing.len())
+                .position(|window| window == &closing[..])
+                .map_or(len, |pos| body + pos + closing.len());
+        } else if rest[0] == b'"' {
+            idx += 1;
+            while idx < len && bytes[idx] != b'"' {
+                idx += if bytes[idx] == b'\\' { 2 } else { 1 };
+            }
+            idx += 1;
+        } else if rest[0] == b'\'' {
+            // A character literal, unless it is a lifetime
+            let literal_len = if rest.get(1) == Some(&b'\\') {
+                rest.get(3..)
+                    .and_then(|escaped| escaped.iter().position(|&b| b == b'\''))
+                    .map(|pos| pos + 4)
+            } else {
+                source[idx + 1..]
+                    .chars()
+                    .next()
+                    .map(|c| c.len_utf8() + 1)
+                    .filter(|&closing| rest.get(closing) == Some(&b'\''))
+                    .map(|closing| closing + 1)
+            };
+            match literal_len {
+                Some(literal_len) => idx += literal_len,
+                None => {
+                    idx += 1;
+                    continue;
+                }
+            }
+        } else {
+            idx += 1;
+            continue;
+        }
+
+        idx = idx.min(len);
+        mask[start..idx].iter_mut().for_each(|code| *code = false);
+    }
+    mask
+}
+
+/// Returns the number of `#` of the raw string literal that starts at `idx`
+/// of `bytes`, if any, eg. 1 for `r#"…"#`.
+fn raw_string_hashes(bytes: &[u8], idx: usize) -> Option<usize> {
+    let prefix_start = if idx > 0 && bytes[idx - 1] == b'b' {
+        idx - 1
+    } else {
+        idx
+    };
+    let follows_ident = prefix_start.checked_sub(1).map_or(false, |prev| {
+        bytes[prev].is_ascii_alphanumeric() || bytes[prev] == b'_'
+    });
+    if bytes[idx] != b'r' || follows_ident {
+        return None;
+    }
+    let hashes = bytes[idx + 1..].iter().take_while(|&&b| b == b'#').count();
+    if bytes.get(idx + 1 + hashes) == Some(&b'"') {
+        Some(hashes)
+    } else {
+        None
+    }
+}
+
+/// Returns the name by which the binaries of an output directory refer to its
+/// library, from the manifest that the crate ends up with.
+fn lib_name(
+    mod_out_path: &path::Path,
+    remap_file: Option<&RemapFile>,
+    cfg: &Config,
+) -> Result<String> {
+    let keep_existing = !cfg.create_manifest || !cfg.overwrite_manifest;
+    cargo_util::lib_name(
+        mod_out_path,
+        keep_existing,
+        remap_file.map(|remap_file| &remap_file.manifest),
+    )
+}
+
+/// Creates the manifest for an output directory.
+fn build_manifest(
+    mod_out_path: &path::Path,
+    plan: &OutputPlan,
+    remap_file: Option<&RemapFile>,
+    cfg: &Config,
+) -> Result<Manifest> {
+    let name = cargo_util::crate_name(mod_out_path);
+    let mut manifest = Manifest::new(&name, cfg.edition.unwrap_or_default());
+    manifest.lib = plan.lib_target.clone();
+    manifest.bins = plan.bin_targets.clone();
+    if let Some(remap_file) = remap_file {
+        manifest.dependencies = remap_file.deps.clone();
+        manifest.merge_user(&remap_file.manifest, cfg.edition)?;
+    }
+    Ok(manifest)
+}
+
+/// The outcome of writing a transpiled file.
+struct WriteOutcome {
+    /// True if the file was written
+    written: bool,
+    /// True if hand edits were merged into the file
+    merged: bool,
+    /// True if merging hand edits left conflict markers in the file
+    conflicted: bool,
+    /// The contents written, if hand edits were merged into them
+    merged_contents: Option<String>,
+    /// The name and key to record in the cache, if caching
+    cache_entry: Option<(String, String)>,
+}
+
+/// Writes a transpiled file unless it's unchanged, merging hand edits made
+/// since the previous transpilation with `merge`.
+fn write_output(
+    job: &OutputJob,
+    cache: Option<&cache::Cache>,
+    module_input_path: &path::Path,
+    mod_out_path: &path::Path,
+    cfg: &Config,
+) -> Result<WriteOutcome> {
+    // Stubs are not cached, so that failed files are retried on the next run.
+    // A matching key alone doesn't make a file fresh here: its output also
+    // depends on the other files of the module through the import map.
+    let cache_entry = match cache {
+        Some(cache) if !job.stub => Some((
+            cache::relative_name(job.in_path, module_input_path),
+            cache.key(job.in_path)?,
+        )),
+        _ => None,
+    };
+
+    // The pristine copy is kept only when merging hand edits
+    let base_path = Some(merge::base_path(&job.out_path, mod_out_path)).filter(|_| cfg.merge);
+
+    // Create subdirectories such as "src/bin"
+    for path in std::iter::once(&job.out_path).chain(&base_path) {
+        if let Some(parent) = path.parent() {
+            if !parent.exists() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+    }
+
+    // Preserve hand edits made since the previous generation
+    let merge = if let Some(base_path) = &base_path {
+        merge::merge(&job.out_path, base_path, &job.contents)?
+    } else {
+        merge::Merge {
+            contents: job.contents.to_string(),
+            merged: false,
+            conflicted: false,
+        }
+    };
+
+    // Rewrite only changed files when watching or caching
+    let written = if cfg.watch || cache.is_some() {
+        write_file_if_changed(&job.out_path, &merge.contents)?
+    } else {
+        write_file(&job.out_path, &merge.contents)?;
+        true
+    };
+    if let Some(base_path) = &base_path {
+        write_file_if_changed(base_path, &job.contents)?;
+    }
+
+    Ok(WriteOutcome {
+        written,
+        merged: merge.merged,
+        conflicted: merge.conflicted,
+        merged_contents: Some(merge.contents).filter(|contents| *contents != job.contents),
+        cache_entry,
+    })
+}
+
+/// Reads the remap file and adds the crates derived from the Python
+/// dependency file, if any. Dependencies in the remap file take precedence.
+fn load_remap_file(cfg: &Config) -> Result<Option<RemapFile>> {
+    let mut remap_file = match &cfg.remap_file {
+        Some(f) => Some(read_remap_file(f)?),
+        None => None,
+    };
+
+    if let Some(python_deps) = &cfg.python_deps {
+        let remap_file = remap_file.get_or_insert_with(|| RemapFile {
+            deps: TomlMap::new(),
+            manifest: TomlMap::new(),
+            pypi: TomlMap::new(),
+            remaps: TomlMap::new(),
+        });
+        for (krate, spec) in python_deps::dependencies(python_deps, &remap_file.pypi)? {
+            if !remap_file.deps.contains_key(&krate) {
+                remap_file.deps.insert(krate, spec);
+            }
+        }
+    }
+
+    Ok(remap_file)
+}
+
+fn read_remap_file(path: impl AsRef<path::Path>) -> Result<RemapFile> {
     let path = path.as_ref();
     let remap_file = fs::read_to_string(path)?;
 
@@ -170,18 +1314,97 @@ fn read_remap_file(
         }
     };
 
-    let deps = match deps_and_remaps
-        .remove("dependencies")
-        .expect("dependencies not found in remap file")
-    {
-        TomlValue::Table(table) => table,
-        value => {
+    let deps = match deps_and_remaps.remove("dependencies") {
+        Some(TomlValue::Table(table)) => table,
+        Some(value) => {
+            return Err(CliError::TomlContentError(value, "table"));
+        }
+        None => {
+            error!("The remap-toml file has to have a [dependencies] table.");
+            return Err(CliError::TomlContentError(
+                TomlValue::Table(deps_and_remaps),
+                "table with a [dependencies] table",
+            ));
+        }
+    };
+    let manifest = match deps_and_remaps.remove("manifest") {
+        Some(TomlValue::Table(table)) => table,
+        Some(value) => {
+            return Err(CliError::TomlContentError(value, "table"));
+        }
+        None => TomlMap::new(),
+    };
+    let pypi = match deps_and_remaps.remove("pypi") {
+        Some(TomlValue::Table(table)) => table,
+        Some(value) => {
             return Err(CliError::TomlContentError(value, "table"));
         }
+        None => TomlMap::new(),
     };
     let remaps: TomlMap<String, TomlValue> = deps_and_remaps.into();
 
-    Ok((deps, remaps))
+    Ok(RemapFile {
+        deps,
+        manifest,
+        pypi,
+        remaps,
+    })
+}
+
+/// Builds the line map of a transpiled file written as `rust_target`. The
+/// traces match the output as serpent generated it, so the map of formatted
+/// output is built for the `unformatted` output and carried over.
+fn build_line_map(
+    file: &TranspiledFile,
+    rust_target: &str,
+    unformatted: Option<&str>,
+) -> Result<LineMap> {
+    let python_source = fs::read_to_string(&file.source_path)?;
+    let trace = |line| file.trace_steps_for_line(line, false);
+    Ok(match unformatted {
+        Some(unformatted) => {
+            LineMap::build(&python_source, unformatted, trace).carry_over(unformatted, rust_target)
+        }
+        None => LineMap::build(&python_source, rust_target, trace),
+    })
+}
+
+/// Formats transpiled files in parallel with the rustfmt configuration of the
+/// crate at `crate_path` and returns their unformatted Rust along with the
+/// time spent formatting each file. Line numbers must be added after
+/// formatting, as they are not valid Rust.
+fn format_files(
+    files: &mut [TranspiledFile],
+    crate_path: Option<&path::Path>,
+    cfg: &Config,
+) -> (Vec<String>, Vec<time::Duration>) {
+    let edition = cfg.edition.unwrap_or_default();
+    let formatted = files
+        .iter()
+        .map(|file| {
+            (
+                file.source_path.as_path(),
+                file.content().rust_target.as_str(),
+            )
+        })
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|(python_path, rust_target)| {
+            let start = time::Instant::now();
+            let formatted = rustfmt::format(rust_target, python_path, crate_path, edition);
+            (formatted, start.elapsed())
+        })
+        .collect::<Vec<_>>();
+    files
+        .iter_mut()
+        .zip(formatted)
+        .map(|(file, (rust_target, elapsed))| {
+            (
+                mem::replace(&mut file.content.rust_target, rust_target),
+                elapsed,
+            )
+        })
+        .unzip()
 }
 
 fn add_line_nbs(s: &str) -> String {
@@ -203,6 +1426,17 @@ fn add_line_nbs(s: &str) -> String {
         .join("\n")
 }
 
+/// Generates a binary target name for an entry point from its path relative
+/// to the module, eg. "scripts/run.py" becomes "scripts-run"
+fn bin_name(path: &path::Path, module_path: &path::Path) -> String {
+    // Unwrap should be safe, because all transpiled files are within the module
+    let relative = path.strip_prefix(module_path).unwrap().with_extension("");
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .join("-")
+}
+
 /// Replaces `from_stem` in `path` with `to_stem`, adds 'src/' and swaps ".py"
 /// into ".rs"
 fn translate(path: &path::Path, from_stem: &path::Path, to_stem: &path::Path) -> path::PathBuf {
@@ -215,3 +1449,51 @@ fn translate(path: &path::Path, from_stem: &path::Path, to_stem: &path::Path) ->
 
     to_stem.join("src").join(rs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_into_lib_rewrites_crate_paths_and_modules() {
+        let contents =
+            "mod a;\npub mod b; // b\nuse crate::a::f;\nfn main() {\n    crate::b::g();\n}\n";
+
+        assert_eq!(
+            paths_into_lib(contents, "my_tool"),
+            "use my_tool::a;\npub use my_tool::b; // b\nuse my_tool::a::f;\nfn main() {\n    my_tool::b::g();\n}\n"
+        );
+    }
+
+    #[test]
+    fn paths_into_lib_keeps_other_paths() {
+        let contents = "mod inline {}\nuse my_crate::a;\n$crate::b!();";
+
+        assert_eq!(paths_into_lib(contents, "lib"), contents);
+    }
+
+    #[test]
+    fn paths_into_lib_keeps_comments_and_literals() {
+        let contents = concat!(
+            "// mod a; uses crate::a\n",
+            "/* crate::a /* nested */ crate::b */ crate::c();\n",
+            "let s = \"crate::a \\\" crate::b\";\n",
+            "let r = r#\"crate::a \" crate::b\"#;\n",
+            "let c = '\"'; fn f<'a>(x: &'a str) { crate::d(x) }\n",
+            "/*\nmod e;\n*/\n",
+        );
+
+        assert_eq!(
+            paths_into_lib(contents, "lib"),
+            contents
+                .replace("*/ crate::c", "*/ lib::c")
+                .replace("{ crate::d", "{ lib::d")
+        );
+    }
+
+    #[test]
+    fn declares_modules_ignores_comments() {
+        assert!(declares_modules("fn main() {}\n    pub mod a;\n"));
+        assert!(!declares_modules("// mod a;\n/* mod b; */\nmod c {}\n"));
+    }
+}
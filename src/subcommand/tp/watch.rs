@@ -0,0 +1,154 @@
+//! Watches the input module and the files shared by all of its Python files,
+//! such as the remap file, and re-transpiles on changes.
+use super::{
+    merge, source_map,
+    stats::Stats,
+    transpile::{finish, transpile_module},
+    Config, Result,
+};
+use crate::{error::CliError, TranspileUnit};
+use fs_err as fs;
+use log::{debug, error, info};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use serpent::TranspileConfig;
+
+use std::{path, sync::mpsc, time};
+
+/// How long to wait for file events to settle before rebuilding.
+const DEBOUNCE_DELAY: time::Duration = time::Duration::from_millis(500);
+
+/// Transpiles the module in `cfg` and keeps re-transpiling it whenever one of
+/// its Python files or the shared inputs, such as the remap file, changes. Runs
+/// until the process is terminated.
+pub fn watch(cfg: &Config) -> Result<()> {
+    let module_path = match &cfg.transpile_unit {
+        TranspileUnit::Module(path) => path,
+        // Unreachable because we verify that this is a module in `resolve_args`
+        TranspileUnit::File(_) | TranspileUnit::Source(_) => unreachable!(),
+    };
+
+    // The watcher reports absolute paths
+    let watched_module = fs::canonicalize(module_path)?;
+    let shared_inputs = cfg
+        .shared_inputs()
+        .into_iter()
+        .map(fs::canonicalize)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    // Watch the directories of the shared inputs instead of the files, because
+    // editors that save by renaming a new file over the old one remove the
+    // watched file. Directories within the module are watched already.
+    let mut input_dirs = shared_inputs
+        .iter()
+        .filter_map(|input| input.parent())
+        .filter(|dir| !dir.starts_with(&watched_module))
+        .collect::<Vec<_>>();
+    input_dirs.sort();
+    input_dirs.dedup();
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE_DELAY)?;
+    watcher.watch(&watched_module, RecursiveMode::Recursive)?;
+    for dir in input_dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    // The output files of the previous rebuild
+    let mut outputs = vec![];
+    rebuild(module_path, cfg, &mut outputs);
+    info!("Watching {:?} for changes", module_path);
+
+    loop {
+        let event = rx.recv().map_err(|_| CliError::WatchDisconnected)?;
+
+        let changed = match &event {
+            DebouncedEvent::Create(p) | DebouncedEvent::Write(p) | DebouncedEvent::Remove(p) => {
+                is_relevant(p, &watched_module, &shared_inputs)
+            }
+            DebouncedEvent::Rename(from, to) => {
+                is_relevant(from, &watched_module, &shared_inputs)
+                    || is_relevant(to, &watched_module, &shared_inputs)
+            }
+            DebouncedEvent::Rescan => true,
+            DebouncedEvent::Error(e, p) => {
+                error!("Watch error for {:?}: {}", p, e);
+                false
+            }
+            _ => false,
+        };
+
+        if changed {
+            debug!("Change detected: {:?}", event);
+            rebuild(module_path, cfg, &mut outputs);
+        }
+    }
+}
+
+/// Re-runs the module transpilation and logs a summary. Errors are logged
+/// instead of returned so that watching can continue. The outputs of removed
+/// Python files are removed from `outputs`, the output files of the previous
+/// rebuild, and from the output directory.
+fn rebuild(module_path: &path::Path, cfg: &Config, outputs: &mut Vec<path::PathBuf>) {
+    let start = time::Instant::now();
+    let mut stats = Stats::default();
+    let result = transpile_module(module_path, TranspileConfig::default(), cfg, &mut stats);
+    if let Err(e) = finish(cfg, &result, &mut stats, start) {
+        error!("Reporting the rebuild failed: {} ({:?})", e, e);
+    }
+    match result {
+        Ok(summary) => {
+            if let Some(current) = summary.outputs {
+                if let Err(e) = remove_stale_outputs(outputs, &current, cfg) {
+                    error!("Removing stale output failed: {} ({:?})", e, e);
+                }
+                *outputs = current;
+            }
+            info!(
+                "Rebuilt in {:.2?}: {} file(s) written, {} unchanged",
+                start.elapsed(),
+                summary.written,
+                summary.unchanged
+            )
+        }
+        Err(e @ CliError::Diagnostics(_)) => error!("Rebuild failed:\n{}", e),
+        Err(e) => error!("Rebuild failed: {} ({:?})", e, e),
+    }
+}
+
+/// Removes the files in `previous` that are not in `current`, as their Python
+/// files were removed, along with their source maps and pristine copies.
+fn remove_stale_outputs(
+    previous: &[path::PathBuf],
+    current: &[path::PathBuf],
+    cfg: &Config,
+) -> Result<()> {
+    let mod_out_path = match &cfg.output {
+        Some(TranspileUnit::Module(path)) => path,
+        // Unreachable because we verify that this is a module in `resolve_args`
+        _ => unreachable!(),
+    };
+    for stale in previous.iter().filter(|path| !current.contains(path)) {
+        info!("Removing {:?}, as its Python file was removed", stale);
+        let companions = vec![
+            stale.clone(),
+            source_map::map_path(stale),
+            merge::base_path(stale, mod_out_path),
+        ];
+        for path in companions.iter().filter(|path| path.exists()) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if a change to `path` should trigger a rebuild: it's one of
+/// the shared inputs or a Python file of the module. Other files in the
+/// directories of the shared inputs are ignored.
+fn is_relevant(
+    path: &path::Path,
+    module_path: &path::Path,
+    shared_inputs: &[path::PathBuf],
+) -> bool {
+    shared_inputs.iter().any(|input| input == path)
+        || (path.starts_with(module_path) && path.extension().map_or(false, |ext| ext == "py"))
+}